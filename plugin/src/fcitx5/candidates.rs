@@ -8,14 +8,16 @@ use nvim_oxi::{
     self as oxi,
     api::{
         self,
-        opts::EchoOpts,
+        opts::{EchoOpts, SetExtmarkOpts},
         types::{
-            WindowConfig, WindowRelativeTo, WindowStyle, WindowTitle,
-            WindowTitlePosition,
+            WindowAnchor, WindowBorder, WindowConfig, WindowRelativeTo, WindowStyle,
+            WindowTitle, WindowTitlePosition,
         },
-        Buffer,
+        Buffer, Window,
     },
+    conversion::FromObject,
     libuv::AsyncHandle,
+    Dictionary, Object,
 };
 use std::{
     collections::VecDeque,
@@ -23,21 +25,114 @@ use std::{
 };
 use unicode_width::UnicodeWidthStr;
 
+use crate::fcitx5::format::FormattedText;
 use crate::lock_logged;
-use crate::plugin::{get_im_window, PLUGIN_NAME};
-use crate::utils::CURSOR_INDICATOR;
+use crate::neovim::{
+    events::PluginEvent,
+    highlights::{
+        formatted_highlight_groups, HL_AUX, HL_CANDIDATE, HL_CANDIDATE_COMMENT,
+        HL_CANDIDATE_SELECTED,
+    },
+    preedit::preedit_chunks,
+};
+use crate::plugin::{
+    cache_current_im,
+    config::{
+        Anchor, BorderStyle, CandidateLayout, PluginConfig, TitlePosition,
+        WindowFollow, WindowOptions,
+    },
+    get_im_window, ContextKey, PLUGIN_NAME,
+};
+
+static IM_WINDOW_NAMESPACE: &str = "fcitx5-ui-rs-window";
+
+/// Prefix of the preedit line in the window
+static PREEDIT_LINE_PREFIX: &str = " \u{f11c}\u{fe0f}  ";
 
 /// Structure for an input method candidate
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Candidate {
     pub display: String,
+    /// Text as sent by fcitx5, with the engine provided comment (e.g. the pronunciation or
+    /// the name of an emoji) appended, if any
     pub text: String,
 }
 
+impl Candidate {
+    /// Best guess at the text and the comment of the candidate.  fcitx5 appends the
+    /// comment after a space without telling where it starts, so this splits at the first
+    /// space, which is wrong for candidates of several words like "New York".
+    pub fn guess_comment(&self) -> (&str, &str) {
+        self.text
+            .split_once(' ')
+            .map(|(text, comment)| (text, comment.trim()))
+            .unwrap_or((self.text.as_str(), ""))
+    }
+}
+
+/// A candidate as shown in the window
+struct CandidateItem {
+    /// Selection marker, label and text
+    text: String,
+    group: &'static str,
+    /// Spaces between the text and the comment
+    padding: usize,
+    comment: String,
+}
+
+impl CandidateItem {
+    fn width(&self) -> usize {
+        let text_width = UnicodeWidthStr::width(self.text.as_str());
+        if self.comment.is_empty() {
+            text_width
+        } else {
+            text_width + self.padding + UnicodeWidthStr::width(self.comment.as_str())
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum UpdateType {
-    Insert(String),
+    /// Insert committed text into the buffer of the input context it came from
+    Insert {
+        text: String,
+        context: ContextKey,
+    },
+    /// Type a character that was held back behind a commit of the same input context
+    Feed {
+        c: char,
+        context: ContextKey,
+    },
     UpdateContent,
+    /// Notify Lua about an IM state change
+    Notify(PluginEvent),
+}
+
+/// A highlight group applied to a byte range of one line of the IM window
+#[derive(Clone, Debug)]
+pub struct IMWindowHighlight {
+    pub line: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+    pub group: &'static str,
+}
+
+/// What clicking a part of the IM window does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClickAction {
+    /// Select the candidate at this index of the current page
+    SelectCandidate(usize),
+    PrevPage,
+    NextPage,
+}
+
+/// Byte range of a window line that reacts to mouse clicks
+#[derive(Clone, Debug)]
+pub struct IMWindowClickTarget {
+    pub line: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+    pub action: ClickAction,
 }
 
 #[derive(Clone, Debug)]
@@ -45,12 +140,24 @@ pub struct IMWindowRenderPlan {
     pub width: u32,
     pub height: u32,
     pub lines: Vec<String>,
+    pub highlights: Vec<IMWindowHighlight>,
+    pub click_targets: Vec<IMWindowClickTarget>,
 }
 
 impl IMWindowRenderPlan {
     pub fn is_visible(&self) -> bool {
         self.width > 0 && self.height > 0 && self.lines.len() > 0
     }
+
+    /// Action of a click at a 0-based line and byte column of the window
+    pub fn click_action(&self, line: usize, col: usize) -> Option<ClickAction> {
+        self.click_targets
+            .iter()
+            .find(|target| {
+                target.line == line && (target.col_start..target.col_end).contains(&col)
+            })
+            .map(|target| target.action)
+    }
 }
 
 /// State for candidate selection UI
@@ -62,10 +169,23 @@ pub struct IMWindowState {
     pub selected_index: usize,
     /// Buffer ID for the candidate window
     pub buffer: Option<Buffer>,
-    /// Current preedit text
+    /// Current preedit text, without the cursor
     pub preedit_text: String,
+    /// `preedit_strs` from fcitx5's `update_client_side_ui` DBus message, with their format
+    /// flags
+    pub preedit_segments: Vec<FormattedText>,
+    /// Byte offset of the cursor inside the preedit
+    pub preedit_cursor: Option<usize>,
     /// joined `aux_up_strs` from fcitx5's `update_client_side_ui` DBus message
     pub aux_up_str: String,
+    /// `aux_up_strs` from fcitx5's `update_client_side_ui` DBus message, with their format
+    /// flags
+    pub aux_up_segments: Vec<FormattedText>,
+    /// joined `aux_down_strs` from fcitx5's `update_client_side_ui` DBus message
+    pub aux_down_str: String,
+    /// `aux_down_strs` from fcitx5's `update_client_side_ui` DBus message, with their
+    /// format flags
+    pub aux_down_segments: Vec<FormattedText>,
     /// Has previous page
     pub has_prev: bool,
     /// Has next page
@@ -74,6 +194,20 @@ pub struct IMWindowState {
     pub rendered_plan: Option<IMWindowRenderPlan>,
     /// Whether the window should be updated
     pub update_queue: VecDeque<UpdateType>,
+    /// Plugin configuration, as passed to `setup()`
+    pub config: PluginConfig,
+    /// Input context a key ending its composition was sent to, whose commit has not been
+    /// received yet.  Characters typed meanwhile are held back in `held_chars`.
+    pub awaiting_commit: Option<ContextKey>,
+    /// Characters typed while a commit was awaited, with the input context they belong to
+    pub held_chars: VecDeque<(char, ContextKey)>,
+    /// Held back characters being typed again, which fcitx5 has already seen
+    pub fed_chars: VecDeque<char>,
+    /// Inserts and feeds taken off `update_queue` but not carried out yet
+    pub inserts_in_flight: usize,
+    /// Input context whose composition the window shows, the one keys were last sent to.
+    /// UI updates from other input contexts are stale and dropped.
+    pub active_context: Option<ContextKey>,
 }
 
 impl IMWindowState {
@@ -83,11 +217,22 @@ impl IMWindowState {
             selected_index: 0,
             buffer: None,
             preedit_text: String::new(),
+            preedit_segments: Vec::new(),
+            preedit_cursor: None,
             aux_up_str: String::new(),
+            aux_up_segments: Vec::new(),
+            aux_down_str: String::new(),
+            aux_down_segments: Vec::new(),
             has_prev: false,
             has_next: false,
             rendered_plan: None,
             update_queue: VecDeque::new(),
+            config: PluginConfig::default(),
+            awaiting_commit: None,
+            held_chars: VecDeque::new(),
+            fed_chars: VecDeque::new(),
+            inserts_in_flight: 0,
+            active_context: None,
         }
     }
 
@@ -98,12 +243,40 @@ impl IMWindowState {
         }
     }
 
+    /// Whether there is an ongoing composition, which is not necessarily shown in the window
+    /// when the preedit is drawn inline
+    pub fn is_composing(&self) -> bool {
+        self.is_visible() || !self.preedit_text.is_empty()
+    }
+
+    /// Whether the preedit gets a line in the window
+    fn shows_preedit_line(&self) -> bool {
+        !self.config.inline_preedit && !self.preedit_text.is_empty()
+    }
+
     pub fn is_showing_current_im(&self) -> bool {
         !self.aux_up_str.is_empty()
+            && self.aux_down_str.is_empty()
             && self.preedit_text.is_empty()
             && self.candidates.is_empty()
     }
 
+    /// Forget everything received from the input context, e.g. after it has been recreated
+    pub fn clear(&mut self) {
+        self.candidates.clear();
+        self.selected_index = 0;
+        self.preedit_text.clear();
+        self.preedit_segments.clear();
+        self.preedit_cursor = None;
+        self.aux_up_str.clear();
+        self.aux_up_segments.clear();
+        self.aux_down_str.clear();
+        self.aux_down_segments.clear();
+        self.has_prev = false;
+        self.has_next = false;
+        self.mark_for_update();
+    }
+
     /// Update candidates list
     pub fn update_candidates(&mut self, candidates: &[Candidate]) {
         self.candidates = candidates.to_owned();
@@ -112,115 +285,199 @@ impl IMWindowState {
         }
     }
 
-    /// Calculate the optimal width for the window based on content
-    fn calculate_window_dimensions(&self) -> (u32, u32) {
-        if self.is_showing_current_im() {
-            return (2, 1);
+    /// The preedit split into highlighted chunks, including the cursor
+    fn preedit_chunks(&self) -> Vec<(String, Vec<&'static str>)> {
+        preedit_chunks(
+            &self.preedit_segments,
+            self.preedit_cursor,
+            &self.config.window.cursor,
+        )
+    }
+
+    /// A separator line spanning `width` cells
+    fn separator_line(&self, width: u32) -> String {
+        let separator = &self.config.window.separator;
+        let separator_width = UnicodeWidthStr::width(separator.as_str()).max(1);
+        separator.repeat(width as usize / separator_width)
+    }
+
+    /// Text and comment of a candidate as shown, see `split_comments`
+    fn candidate_parts(&self, idx: usize) -> (&str, &str) {
+        let candidate = &self.candidates[idx];
+        if self.config.window.split_comments {
+            candidate.guess_comment()
+        } else {
+            (candidate.text.as_str(), "")
         }
+    }
 
-        // Calculate width based on content
-        let mut width = if self.aux_up_str.is_empty() {
-            30
+    /// Selection marker, label and text of a candidate, and its highlight group
+    fn candidate_text(&self, idx: usize) -> (String, &'static str) {
+        let candidate = &self.candidates[idx];
+        let (text, _) = self.candidate_parts(idx);
+        let selected_marker = self.config.window.selected_marker.as_str();
+        let (marker, group) = if idx == self.selected_index {
+            (selected_marker.to_owned(), HL_CANDIDATE_SELECTED)
         } else {
-            self.aux_up_str.len().try_into().unwrap_or(30)
-        }; // Start with reasonable default
-
-        if !self.candidates.is_empty() {
-            // Find the longest candidate text
-            let max_candidate_len = self
-                .candidates
-                .iter()
-                .map(|c| c.display.len() + c.text.len() + 3) // +3 for marker and space
-                .max()
-                .unwrap_or(0);
-
-            // Find longest preedit text
-            let preedit_len = if !self.preedit_text.is_empty() {
-                self.preedit_text.len() + 4 // "⌨  " prefix
-            } else {
-                0
-            };
+            (
+                " ".repeat(UnicodeWidthStr::width(selected_marker)),
+                HL_CANDIDATE,
+            )
+        };
+        (format!("{} {} {}", marker, candidate.display, text), group)
+    }
 
-            // Find max length needed
-            let needed_len = std::cmp::max(max_candidate_len, preedit_len);
-
-            // Use gradual growth function instead of direct 1:1 mapping
-            // For small changes, grow slowly; for larger changes, grow more
-            if needed_len > 0 {
-                if needed_len <= 20 {
-                    // For small text, use fixed size
-                    width = 30;
-                } else if needed_len <= 40 {
-                    // For medium text, grow at about 80% of text growth
-                    width = 30u32.saturating_add(
-                        ((needed_len.saturating_sub(20)) as f32 * 0.8) as u32,
-                    );
-                } else {
-                    // For larger text, grow at about 90% rate
-                    width = 46u32.saturating_add(
-                        ((needed_len.saturating_sub(40)) as f32 * 0.9) as u32,
-                    );
-                }
+    /// A candidate as shown in the window.  In the vertical layout, comments are aligned in
+    /// a column unless disabled.
+    fn candidate_item(&self, idx: usize) -> CandidateItem {
+        let (text, group) = self.candidate_text(idx);
+        let padding = match self.config.candidate_layout {
+            CandidateLayout::Vertical if self.config.window.align_comments => {
+                let widest = (0..self.candidates.len())
+                    .map(|idx| {
+                        UnicodeWidthStr::width(self.candidate_text(idx).0.as_str())
+                    })
+                    .max()
+                    .unwrap_or(0);
+                widest - UnicodeWidthStr::width(text.as_str()) + 2
+            }
+            _ => 1,
+        };
+        CandidateItem {
+            text,
+            group,
+            padding,
+            comment: self.candidate_parts(idx).1.to_owned(),
+        }
+    }
 
-                // Add minimal padding for aesthetics (reduced from 4 to 2)
-                width += 2;
+    /// Display width of a line of candidates, items are separated by a single space
+    fn candidate_row_width(&self, row: &[usize]) -> usize {
+        let items_width: usize = row
+            .iter()
+            .map(|idx| self.candidate_item(*idx).width())
+            .sum();
+        items_width + row.len().saturating_sub(1)
+    }
 
-                // Apply hysteresis to prevent small oscillations
-                // Only change size if it would be at least 4 chars different
-                let maybe_window = {
-                    let im_window = get_im_window();
-                    let im_window_guard = im_window.lock().unwrap();
-                    im_window_guard.as_ref().cloned()
-                };
-                if let Some(window) = maybe_window {
-                    if window.is_valid() {
-                        if let Ok(config) = window.get_config() {
-                            let current_width = config.width.unwrap_or(0);
-                            width = {
-                                let width_i32 = width as i32;
-                                let current_width_i32 = current_width as i32;
-                                if let Some(diff) =
-                                    width_i32.checked_sub(current_width_i32)
-                                {
-                                    if diff.abs() < 4 {
-                                        current_width
-                                    } else {
-                                        width
-                                    }
-                                } else {
-                                    // Handle potential overflow case if the difference can't be calculated
-                                    // This is an edge case where the values are drastically different
-                                    width // Keep the new width
-                                }
-                            };
-                        }
+    /// Indices of the candidates shown on each line of the window
+    fn candidate_rows(&self) -> Vec<Vec<usize>> {
+        match self.config.candidate_layout {
+            CandidateLayout::Vertical => {
+                (0..self.candidates.len()).map(|idx| vec![idx]).collect()
+            }
+            CandidateLayout::Horizontal => {
+                // Wrap so that every line fits in the widest window
+                let window = &self.config.window;
+                let max_row_width =
+                    window.max_width.saturating_sub(window.padding) as usize;
+                let mut rows: Vec<Vec<usize>> = Vec::new();
+                for idx in 0..self.candidates.len() {
+                    let item_width = self.candidate_row_width(&[idx]);
+                    let fits = rows.last().is_some_and(|row| {
+                        self.candidate_row_width(row) + 1 + item_width <= max_row_width
+                    });
+                    match rows.last_mut() {
+                        Some(row) if fits => row.push(idx),
+                        _ => rows.push(vec![idx]),
                     }
                 }
+                rows
             }
+        }
+    }
 
-            // Ensure width is at least 20 and at most 60 characters
-            width = width.clamp(20, 60);
+    /// Width of the current IM window, if it is open
+    fn current_window_width() -> Option<u32> {
+        let maybe_window = {
+            let im_window = get_im_window();
+            let im_window_guard = im_window.lock().unwrap();
+            im_window_guard.as_ref().cloned()
+        };
+        maybe_window
+            .filter(|window| window.is_valid())
+            .and_then(|window| window.get_config().ok())
+            .and_then(|config| config.width)
+    }
+
+    /// Calculate the optimal size for the window based on the display width of its content
+    fn calculate_window_dimensions(&self) -> (u32, u32) {
+        if self.is_showing_current_im() {
+            return (2, 1);
         }
 
-        // Calculate height based on number of items plus headers/footers
-        let base_height = if !self.preedit_text.is_empty() {
+        let window = &self.config.window;
+        let candidate_rows = self.candidate_rows();
+
+        // Find the widest line
+        let aux_width = UnicodeWidthStr::width(self.aux_up_str.as_str())
+            .max(UnicodeWidthStr::width(self.aux_down_str.as_str()));
+        let preedit_width = if self.shows_preedit_line() {
+            UnicodeWidthStr::width(PREEDIT_LINE_PREFIX)
+                + self
+                    .preedit_chunks()
+                    .iter()
+                    .map(|(text, _)| UnicodeWidthStr::width(text.as_str()))
+                    .sum::<usize>()
+        } else {
+            0
+        };
+        let candidates_width = candidate_rows
+            .iter()
+            .map(|row| self.candidate_row_width(row))
+            .max()
+            .unwrap_or(0);
+        let needed_width = aux_width.max(preedit_width).max(candidates_width) as u32;
+
+        let max_width = window.max_width.max(window.min_width);
+        let mut width = needed_width
+            .saturating_add(window.padding)
+            .clamp(window.min_width, max_width);
+
+        // Apply hysteresis to prevent small oscillations, as long as the content still fits
+        if let Some(current_width) = Self::current_window_width() {
+            if current_width >= needed_width.min(max_width)
+                && current_width.abs_diff(width) < window.hysteresis
+            {
+                width = current_width.clamp(window.min_width, max_width);
+            }
+        }
+
+        // Calculate height based on the lines `build_render_plan` produces
+        let has_body = self.shows_preedit_line() || !candidate_rows.is_empty();
+        let aux_height = match (self.aux_up_str.is_empty(), has_body) {
+            (true, _) => 0u32,
+            (false, false) => 1, // aux line
+            (false, true) => 2,  // aux line + separator
+        };
+        let preedit_height = if self.shows_preedit_line() {
             2u32
         } else {
             0u32
-        }; // Header + separator (or nothing)
+        }; // Preedit line + separator (or nothing)
+        let aux_down_height = match (
+            self.aux_down_str.is_empty(),
+            has_body || !self.aux_up_str.is_empty(),
+        ) {
+            (true, _) => 0u32,
+            (false, false) => 1, // aux line
+            (false, true) => 2,  // separator + aux line
+        };
         let paging_height = if self.has_prev || self.has_next {
             2u32
         } else {
             0u32
         }; // Separator + paging line
-        let content_height = self.candidates.len() as u32;
+        let content_height = candidate_rows.len() as u32;
 
-        let total_height = base_height
+        let total_height = aux_height
+            .saturating_add(preedit_height)
             .saturating_add(content_height)
+            .saturating_add(aux_down_height)
             .saturating_add(paging_height);
 
-        // Calculate total height - remove the extra line
-        let height = total_height.clamp(3, 15);
+        let height = total_height
+            .clamp(window.min_height, window.max_height.max(window.min_height));
 
         (width, height)
     }
@@ -229,56 +486,147 @@ impl IMWindowState {
         let (width, height) = self.calculate_window_dimensions();
 
         let mut lines = Vec::new();
+        let mut highlights = Vec::new();
+        let mut click_targets = Vec::new();
 
         if !self.aux_up_str.is_empty() {
-            lines.push(self.aux_up_str.clone());
-            if !self.preedit_text.is_empty() || !self.candidates.is_empty() {
-                lines.push("\u{2500}".repeat(width as usize));
+            let mut aux_line = String::new();
+            for segment in &self.aux_up_segments {
+                let col_start = aux_line.len();
+                aux_line.push_str(&segment.text);
+                for group in formatted_highlight_groups(HL_AUX, segment) {
+                    highlights.push(IMWindowHighlight {
+                        line: lines.len(),
+                        col_start,
+                        col_end: aux_line.len(),
+                        group,
+                    });
+                }
+            }
+            lines.push(aux_line);
+            if self.shows_preedit_line() || !self.candidates.is_empty() {
+                lines.push(self.separator_line(width));
             }
         }
 
-        if !self.preedit_text.is_empty() {
-            lines.push(format!(" \u{f11c}\u{fe0f}  {}", self.preedit_text));
-            lines.push("\u{2500}".repeat(width as usize));
+        if self.shows_preedit_line() {
+            let mut preedit_line = PREEDIT_LINE_PREFIX.to_owned();
+            for (text, groups) in self.preedit_chunks() {
+                let col_start = preedit_line.len();
+                preedit_line.push_str(&text);
+                for group in groups {
+                    highlights.push(IMWindowHighlight {
+                        line: lines.len(),
+                        col_start,
+                        col_end: preedit_line.len(),
+                        group,
+                    });
+                }
+            }
+            lines.push(preedit_line);
+            lines.push(self.separator_line(width));
         }
 
-        for (idx, candidate) in self.candidates.iter().enumerate() {
-            let marker = if idx == self.selected_index {
-                "\u{25ba}"
-            } else {
-                " "
-            };
-            lines.push(format!(
-                "{} {} {}",
-                marker, candidate.display, candidate.text
-            ));
+        for row in self.candidate_rows() {
+            let mut candidate_line = String::new();
+            for idx in row {
+                if !candidate_line.is_empty() {
+                    candidate_line.push(' ');
+                }
+                let item = self.candidate_item(idx);
+                let col_start = candidate_line.len();
+                candidate_line.push_str(&item.text);
+                highlights.push(IMWindowHighlight {
+                    line: lines.len(),
+                    col_start,
+                    col_end: candidate_line.len(),
+                    group: item.group,
+                });
+                if !item.comment.is_empty() {
+                    candidate_line.push_str(&" ".repeat(item.padding));
+                    let comment_start = candidate_line.len();
+                    candidate_line.push_str(&item.comment);
+                    highlights.push(IMWindowHighlight {
+                        line: lines.len(),
+                        col_start: comment_start,
+                        col_end: candidate_line.len(),
+                        group: HL_CANDIDATE_COMMENT,
+                    });
+                }
+                click_targets.push(IMWindowClickTarget {
+                    line: lines.len(),
+                    col_start,
+                    col_end: candidate_line.len(),
+                    action: ClickAction::SelectCandidate(idx),
+                });
+            }
+            lines.push(candidate_line);
+        }
+
+        if !self.aux_down_str.is_empty() {
+            if !lines.is_empty() {
+                lines.push(self.separator_line(width));
+            }
+            let mut aux_line = String::new();
+            for segment in &self.aux_down_segments {
+                let col_start = aux_line.len();
+                aux_line.push_str(&segment.text);
+                for group in formatted_highlight_groups(HL_AUX, segment) {
+                    highlights.push(IMWindowHighlight {
+                        line: lines.len(),
+                        col_start,
+                        col_end: aux_line.len(),
+                        group,
+                    });
+                }
+            }
+            lines.push(aux_line);
         }
 
         if self.has_prev || self.has_next {
-            lines.push("\u{2500}".repeat(width as usize));
+            lines.push(self.separator_line(width));
+
+            let prev_part = format!("{} Prev", self.config.window.prev_marker);
+            let next_part = format!("Next {}", self.config.window.next_marker);
+
+            let prev_width = UnicodeWidthStr::width(prev_part.as_str());
+            let next_width = UnicodeWidthStr::width(next_part.as_str());
 
             let prev_part = if self.has_prev {
-                "\u{25c4} Prev"
+                prev_part
             } else {
-                "      "
+                " ".repeat(prev_width)
             };
             let next_part = if self.has_next {
-                "Next \u{25ba}"
+                next_part
             } else {
-                "      "
+                " ".repeat(next_width)
             };
 
-            let prev_width = UnicodeWidthStr::width(prev_part);
-            let next_width = UnicodeWidthStr::width(next_part);
-
             let total_width = width as usize;
 
             let mut paging_line = String::with_capacity(total_width);
-            paging_line.push_str(prev_part);
+            paging_line.push_str(&prev_part);
 
             let spaces_needed = total_width.saturating_sub(prev_width + next_width);
             paging_line.push_str(&" ".repeat(spaces_needed));
-            paging_line.push_str(next_part);
+            if self.has_prev {
+                click_targets.push(IMWindowClickTarget {
+                    line: lines.len(),
+                    col_start: 0,
+                    col_end: prev_part.len(),
+                    action: ClickAction::PrevPage,
+                });
+            }
+            if self.has_next {
+                click_targets.push(IMWindowClickTarget {
+                    line: lines.len(),
+                    col_start: paging_line.len(),
+                    col_end: paging_line.len() + next_part.len(),
+                    action: ClickAction::NextPage,
+                });
+            }
+            paging_line.push_str(&next_part);
 
             paging_line.push_str("");
 
@@ -289,6 +637,22 @@ impl IMWindowState {
             width,
             height,
             lines,
+            highlights,
+            click_targets,
+        }
+    }
+
+    /// Columns between the cursor and what the window follows
+    fn window_col_offset(&self) -> i32 {
+        match (self.config.window.follow, self.config.inline_preedit) {
+            // The cursor glyph of an inline preedit is drawn after the preedit text before
+            // it, while the real cursor stays at the start of the preedit
+            (WindowFollow::Cursor, true) => self
+                .preedit_cursor
+                .and_then(|pos| self.preedit_text.get(..pos))
+                .map(|before_cursor| UnicodeWidthStr::width(before_cursor) as i32)
+                .unwrap_or(0),
+            _ => 0,
         }
     }
 
@@ -345,36 +709,20 @@ impl IMWindowState {
             }
         };
 
+        let options = self_immut.config.window.clone();
         let width = plan.width;
         let height = plan.height;
-
-        // Create window options
-        let mut opts_builder = WindowConfig::builder();
-        let opts_builder = opts_builder
-            .relative(WindowRelativeTo::Cursor)
-            .zindex(0x7fff)
-            .row(1)
-            .col(0)
-            .width(width)
-            .height(height)
-            .focusable(false)
-            .style(WindowStyle::Minimal);
-        let opts_builder = if width > 2 && height > 1 {
-            opts_builder
-                .title(WindowTitle::SimpleString(" Fcitx5 ".to_owned().into()))
-                .title_pos(WindowTitlePosition::Center)
-        } else {
-            opts_builder
-        };
-        let opts = opts_builder.build();
+        let col_offset = self_immut.window_col_offset();
 
         oxi::schedule({
             let im_window = get_im_window();
             let buffer = buffer.clone();
-            let width = width;
-            let height = height;
             // Open the window with our buffer on the main thread
             move |_| {
+                // Create window options, placed according to where the cursor is now
+                let placement = place_window(&options, width, height, col_offset);
+                let opts = window_config(&options, width, height, &placement);
+
                 // NB: Must perform the two operations:
                 //  1. check if the window exists (here), and
                 //  2. condition on its existence/non-existenct (below if-else block)
@@ -388,40 +736,24 @@ impl IMWindowState {
                 if let Some(mut window) = existing_window {
                     if window.is_valid() {
                         let _ = window.set_config(&opts);
+                        // A new config drops what `window_config` doesn't know about
+                        enable_mouse(&window);
                     }
 
                     let mut im_window_guard = im_window.lock().unwrap();
                     *im_window_guard = Some(window);
                 } else {
-                    // Rebuild the window config using the captured dimensions
-                    let mut opts_builder = WindowConfig::builder();
-                    let opts_builder = opts_builder
-                        .relative(WindowRelativeTo::Cursor)
-                        .zindex(0x7fff)
-                        .row(1)
-                        .col(0)
-                        .width(width)
-                        .height(height)
-                        .focusable(false)
-                        .style(WindowStyle::Minimal);
-                    let opts_builder = if width > 2 && height > 1 {
-                        opts_builder
-                            .title(WindowTitle::SimpleString(
-                                " Fcitx5 ".to_owned().into(),
-                            ))
-                            .title_pos(WindowTitlePosition::Center)
-                    } else {
-                        opts_builder
-                    };
-                    let opts = opts_builder.build();
-
                     match api::open_win(&buffer, false, &opts) {
                         Ok(window) => {
+                            let win_opts =
+                                OptionOpts::builder().win(window.clone()).build();
+                            let _ = set_option_value("wrap", true, &win_opts);
                             let _ = set_option_value(
-                                "wrap",
-                                true,
-                                &OptionOpts::builder().win(window.clone()).build(),
+                                "winblend",
+                                options.winblend as i64,
+                                &win_opts,
                             );
+                            enable_mouse(&window);
 
                             let old_window = {
                                 let mut im_window_guard = im_window.lock().unwrap();
@@ -450,9 +782,11 @@ impl IMWindowState {
 
     pub fn apply_render_plan_to_buffer(buffer: &Buffer, plan: &IMWindowRenderPlan) {
         let lines_clone = plan.lines.clone();
+        let highlights_clone = plan.highlights.clone();
         oxi::schedule({
             let mut buffer = buffer.clone();
             let lines = lines_clone;
+            let highlights = highlights_clone;
             move |_| {
                 if !buffer.is_valid() {
                     return;
@@ -461,26 +795,277 @@ impl IMWindowState {
                 if let Ok(line_count) = buffer.line_count() {
                     let _ = buffer.set_lines(0..line_count, true, lines);
                 }
+
+                let ns_id = api::create_namespace(IM_WINDOW_NAMESPACE);
+                let _ = buffer.clear_namespace(ns_id, 0..);
+                for hl in highlights {
+                    let _ = buffer.set_extmark(
+                        ns_id,
+                        hl.line,
+                        hl.col_start,
+                        &SetExtmarkOpts::builder()
+                            .end_row(hl.line)
+                            .end_col(hl.col_end)
+                            .hl_group(hl.group)
+                            .build(),
+                    );
+                }
             }
         });
     }
 
-    pub fn mark_for_insert(&mut self, text: String) {
-        self.update_queue.push_back(UpdateType::Insert(text));
+    pub fn mark_for_insert(&mut self, text: String, context: ContextKey) {
+        self.update_queue
+            .push_back(UpdateType::Insert { text, context });
+    }
+
+    /// Whether a character typed now would overtake text that is still to be inserted
+    pub fn must_hold_char(&self) -> bool {
+        self.awaiting_commit.is_some()
+            || !self.held_chars.is_empty()
+            || !self.fed_chars.is_empty()
+            || self.inserts_in_flight > 0
+            || self.update_queue.iter().any(|update| {
+                matches!(update, UpdateType::Insert { .. } | UpdateType::Feed { .. })
+            })
+    }
+
+    /// Hold `c` back until the awaited commit has been queued, or queue it right away
+    /// behind the pending inserts
+    pub fn hold_char(&mut self, c: char, context: ContextKey) {
+        if self.awaiting_commit.is_some() {
+            self.held_chars.push_back((c, context));
+        } else {
+            self.update_queue.push_back(UpdateType::Feed { c, context });
+        }
+    }
+
+    /// Queue the characters held back behind the awaited commit of `context`
+    pub fn release_held_chars(&mut self, context: ContextKey) {
+        if self.awaiting_commit != Some(context) {
+            return;
+        }
+        self.awaiting_commit = None;
+        while let Some((c, context)) = self.held_chars.pop_front() {
+            self.update_queue.push_back(UpdateType::Feed { c, context });
+        }
+    }
+
+    /// Queue all held back characters without waiting for the commit anymore, e.g. when
+    /// leaving insert mode
+    pub fn flush_held_chars(&mut self) {
+        if let Some(context) = self.awaiting_commit {
+            self.release_held_chars(context);
+        }
+        self.fed_chars.clear();
     }
 
     pub fn mark_for_update(&mut self) {
         self.update_queue.push_back(UpdateType::UpdateContent);
     }
 
+    pub fn preedit_event(&self) -> PluginEvent {
+        PluginEvent::PreeditChanged {
+            text: self.preedit_text.clone(),
+            cursor: self.preedit_cursor,
+        }
+    }
+
+    pub fn candidates_event(&self) -> PluginEvent {
+        PluginEvent::Candidates {
+            candidates: self.candidates.clone(),
+            selected_index: self.selected_index,
+            has_prev: self.has_prev,
+            has_next: self.has_next,
+            split_comments: self.config.window.split_comments,
+        }
+    }
+
+    pub fn mark_for_notify(&mut self, event: PluginEvent) {
+        self.update_queue.push_back(UpdateType::Notify(event));
+    }
+
     pub fn pop_update(&mut self) -> Option<UpdateType> {
         self.update_queue.pop_front()
     }
 }
 
+/// Where the IM window goes, relative to the cursor
+struct WindowPlacement {
+    anchor: Anchor,
+    row: i32,
+    col: i32,
+}
+
+fn get_int_option(name: &str) -> Option<i64> {
+    api::get_option_value::<i64>(name, &OptionOpts::default()).ok()
+}
+
+/// 1-based screen position of the cursor of the current window.  Unlike `screenrow()`
+/// and `screencol()`, this doesn't depend on the screen having been redrawn since the
+/// cursor moved.
+fn cursor_screen_pos() -> Option<(i64, i64)> {
+    let win = api::get_current_win();
+    let (row, col) = win.get_cursor().ok()?;
+    let pos = api::call_function::<_, Dictionary>(
+        "screenpos",
+        (win.handle(), row as i64, col as i64 + 1),
+    )
+    .ok()?;
+    let position = |key: &str| {
+        pos.get(key)
+            .and_then(|value| i64::from_object(value.clone()).ok())
+            .unwrap_or(0)
+    };
+    // Zero when the cursor is not displayed
+    let (row, col) = (position("row"), position("col"));
+    (row > 0 && col > 0).then_some((row, col))
+}
+
+/// Place the IM window at the configured offset from the cursor, flipped and shifted
+/// according to the options so that it stays inside the screen.  Must be called on the main
+/// thread.
+fn place_window(
+    options: &WindowOptions,
+    width: u32,
+    height: u32,
+    col_offset: i32,
+) -> WindowPlacement {
+    let mut placement = WindowPlacement {
+        anchor: options.anchor,
+        row: options.row,
+        col: options.col.saturating_add(col_offset),
+    };
+
+    let ((screen_row, screen_col), lines, columns) = match (
+        cursor_screen_pos(),
+        get_int_option("lines"),
+        get_int_option("columns"),
+    ) {
+        (Some(screen_pos), Some(lines), Some(columns)) => (screen_pos, lines, columns),
+        _ => return placement,
+    };
+    let cmdheight = get_int_option("cmdheight").unwrap_or(1);
+    let statusline_height = match get_int_option("laststatus") {
+        Some(0) => 0,
+        _ => 1,
+    };
+
+    let border_size = match options.border {
+        BorderStyle::None => 0,
+        BorderStyle::Shadow => 1,
+        _ => 2,
+    };
+    let outer_height = i64::from(height) + border_size;
+    let outer_width = i64::from(width) + border_size;
+
+    // 0-based cursor position on the screen
+    let cursor_row = screen_row - 1;
+    let cursor_col = screen_col - 1;
+    let bottom_limit = lines - cmdheight - statusline_height;
+
+    if options.flip {
+        let row = i64::from(placement.row);
+        let (north, south) = match placement.anchor {
+            Anchor::NorthWest | Anchor::NorthEast => (true, false),
+            Anchor::SouthWest | Anchor::SouthEast => (false, true),
+        };
+        let fits_below = cursor_row + 1 + outer_height <= bottom_limit;
+        let fits_above = cursor_row >= outer_height;
+        let flip =
+            (north && cursor_row + row + outer_height > bottom_limit && fits_above)
+                || (south && cursor_row + row - outer_height < 0 && fits_below);
+        if flip {
+            placement.anchor = match placement.anchor {
+                Anchor::NorthWest => Anchor::SouthWest,
+                Anchor::NorthEast => Anchor::SouthEast,
+                Anchor::SouthWest => Anchor::NorthWest,
+                Anchor::SouthEast => Anchor::NorthEast,
+            };
+            // Row 1 (the line below the cursor) becomes row 0 (the cursor line's top edge)
+            // and vice versa
+            placement.row = 1 - placement.row;
+        }
+    }
+
+    if options.shift {
+        let col = i64::from(placement.col);
+        placement.col = match placement.anchor {
+            Anchor::NorthWest | Anchor::SouthWest
+                if cursor_col + col + outer_width > columns =>
+            {
+                (columns - outer_width - cursor_col).max(-cursor_col) as i32
+            }
+            Anchor::NorthEast | Anchor::SouthEast
+                if cursor_col + col - outer_width < 0 =>
+            {
+                (outer_width - cursor_col).min(columns - cursor_col) as i32
+            }
+            _ => placement.col,
+        };
+    }
+
+    placement
+}
+
+/// Build the config of the IM window from the user's options
+/// Make the IM window clickable to select candidates while it stays unfocusable, see
+/// `mouse` in `:h nvim_open_win()`
+fn enable_mouse(window: &Window) {
+    let _ = api::call_function::<_, Object>(
+        "nvim_win_set_config",
+        (window.handle(), Dictionary::from_iter([("mouse", true)])),
+    );
+}
+
+fn window_config(
+    options: &WindowOptions,
+    width: u32,
+    height: u32,
+    placement: &WindowPlacement,
+) -> WindowConfig {
+    let mut opts_builder = WindowConfig::builder();
+    let opts_builder = opts_builder
+        .relative(WindowRelativeTo::Cursor)
+        .anchor(match placement.anchor {
+            Anchor::NorthWest => WindowAnchor::NorthWest,
+            Anchor::NorthEast => WindowAnchor::NorthEast,
+            Anchor::SouthWest => WindowAnchor::SouthWest,
+            Anchor::SouthEast => WindowAnchor::SouthEast,
+        })
+        .zindex(options.zindex)
+        .row(placement.row)
+        .col(placement.col)
+        .width(width)
+        .height(height)
+        .focusable(false)
+        .style(WindowStyle::Minimal)
+        .border(match options.border {
+            BorderStyle::None => WindowBorder::None,
+            BorderStyle::Single => WindowBorder::Single,
+            BorderStyle::Double => WindowBorder::Double,
+            BorderStyle::Rounded => WindowBorder::Rounded,
+            BorderStyle::Solid => WindowBorder::Solid,
+            BorderStyle::Shadow => WindowBorder::Shadow,
+        });
+    let opts_builder = if width > 2 && height > 1 && !options.title.is_empty() {
+        opts_builder
+            .title(WindowTitle::SimpleString(options.title.clone().into()))
+            .title_pos(match options.title_pos {
+                TitlePosition::Left => WindowTitlePosition::Left,
+                TitlePosition::Center => WindowTitlePosition::Center,
+                TitlePosition::Right => WindowTitlePosition::Right,
+            })
+    } else {
+        opts_builder
+    };
+    opts_builder.build()
+}
+
 /// Setup message receivers to listen for Fcitx5 candidate updates
 pub fn setup_im_window_receivers(
     ctx: &InputContextProxyBlocking<'static>,
+    context: ContextKey,
     im_window_state: Arc<Mutex<IMWindowState>>,
     trigger: AsyncHandle,
 ) -> Result<()> {
@@ -513,31 +1098,83 @@ pub fn setup_im_window_receivers(
 
                                 // Extract preedit text
                                 let mut preedit_text = String::new();
-                                for (text, _) in args.preedit_strs() {
+                                let mut preedit_segments = Vec::new();
+                                for (text, format) in args.preedit_strs() {
                                     preedit_text.push_str(text);
+                                    preedit_segments.push(FormattedText {
+                                        text: text.to_string(),
+                                        format: *format,
+                                    });
                                 }
+                                let preedit_cursor =
+                                    usize::try_from(args.preedit_cursor).ok();
 
                                 let mut aux_up_str = String::new();
-                                for (text, _) in args.aux_up_strs() {
+                                let mut aux_up_segments = Vec::new();
+                                for (text, format) in args.aux_up_strs() {
                                     aux_up_str.push_str(text);
+                                    aux_up_segments.push(FormattedText {
+                                        text: text.to_string(),
+                                        format: *format,
+                                    });
                                 }
 
-                                if let Ok(pos) = args.preedit_cursor.try_into() {
-                                    preedit_text.insert(pos, CURSOR_INDICATOR);
+                                let mut aux_down_str = String::new();
+                                let mut aux_down_segments = Vec::new();
+                                for (text, format) in args.aux_down_strs() {
+                                    aux_down_str.push_str(text);
+                                    aux_down_segments.push(FormattedText {
+                                        text: text.to_string(),
+                                        format: *format,
+                                    });
                                 }
 
                                 // Update our candidate state
                                 if let Ok(mut guard) = im_window_state.lock() {
+                                    // A key can end the composition without committing
+                                    // anything.  fcitx5 sends a commit before the UI
+                                    // update, so nothing is awaited anymore.
+                                    guard.release_held_chars(context);
+
+                                    // Late update from an input context we moved away
+                                    // from, e.g. after it was reset
+                                    if guard.active_context != Some(context) {
+                                        continue;
+                                    }
+
+                                    let selected_index =
+                                        usize::try_from(cursor_idx).unwrap_or(0);
+                                    let preedit_changed = guard.preedit_text
+                                        != preedit_text
+                                        || guard.preedit_cursor != preedit_cursor;
+                                    let candidates_changed = guard.candidates
+                                        != candidates
+                                        || guard.selected_index != selected_index
+                                        || guard.has_prev != args.has_prev
+                                        || guard.has_next != args.has_next;
+
                                     guard.update_candidates(&candidates);
                                     guard.preedit_text = preedit_text;
+                                    guard.preedit_segments = preedit_segments;
+                                    guard.preedit_cursor = preedit_cursor;
                                     guard.aux_up_str = aux_up_str;
+                                    guard.aux_up_segments = aux_up_segments;
+                                    guard.aux_down_str = aux_down_str;
+                                    guard.aux_down_segments = aux_down_segments;
                                     guard.has_prev = args.has_prev;
                                     guard.has_next = args.has_next;
-                                    guard.selected_index =
-                                        usize::try_from(cursor_idx).unwrap_or(0);
+                                    guard.selected_index = selected_index;
                                     // args.cursor_idx().try_into().unwrap_or(0);
 
                                     guard.mark_for_update();
+                                    if preedit_changed {
+                                        let event = guard.preedit_event();
+                                        guard.mark_for_notify(event);
+                                    }
+                                    if candidates_changed {
+                                        let event = guard.candidates_event();
+                                        guard.mark_for_notify(event);
+                                    }
                                 }
                                 let _ = trigger.send();
                             }
@@ -570,8 +1207,13 @@ pub fn setup_im_window_receivers(
                             if let Ok(mut guard) = im_window_state.lock() {
                                 // Insert, if anything
                                 if !text_to_insert.is_empty() {
-                                    guard.mark_for_insert(args.text.to_owned());
+                                    guard
+                                        .mark_for_insert(args.text.to_owned(), context);
+                                    guard.mark_for_notify(PluginEvent::Commit(
+                                        text_to_insert,
+                                    ));
                                 }
+                                guard.release_held_chars(context);
                             }
                             let _ = trigger.send();
                         }
@@ -587,5 +1229,36 @@ pub fn setup_im_window_receivers(
         }
     });
 
+    // Spawn thread to handle input method changes
+    std::thread::spawn({
+        let trigger = trigger.clone();
+        let current_im_ctx = ctx.clone();
+        let im_window_state = im_window_state.clone();
+
+        move || match current_im_ctx.receive_current_im() {
+            Ok(current_im_signal) => {
+                for signal in current_im_signal {
+                    if let Ok(args) = signal.args() {
+                        cache_current_im(context, args.unique_name.to_owned());
+                        if let Ok(mut guard) = im_window_state.lock() {
+                            guard.mark_for_notify(PluginEvent::IMChanged {
+                                name: args.name.to_owned(),
+                                unique_name: args.unique_name.to_owned(),
+                                language: args.lang_code.to_owned(),
+                            });
+                        }
+                        let _ = trigger.send();
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: fcitx5-ui-rs: Failed to receive current IM signals: {}",
+                    PLUGIN_NAME, e,
+                );
+            }
+        }
+    });
+
     Ok(())
 }
@@ -30,3 +30,10 @@ pub fn prepare() -> Result<
 
     Ok(Some((controller, ctx)))
 }
+
+/// Destroys the input context and closes its connection, which ends the signal iterators
+/// of its receiver threads so that they exit
+pub fn disconnect(ctx: InputContextProxyBlocking<'static>) {
+    let _ = ctx.destroy_ic();
+    let _ = ctx.inner().connection().clone().close();
+}
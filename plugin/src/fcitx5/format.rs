@@ -0,0 +1,25 @@
+//! Fcitx5 text formatting
+
+/// Bits of fcitx5's `TextFormatFlag`, as sent along with preedit and aux strings
+pub struct TextFormatFlag;
+
+impl TextFormatFlag {
+    pub const UNDERLINE: i32 = 1 << 3;
+    pub const HIGHLIGHT: i32 = 1 << 4;
+    pub const BOLD: i32 = 1 << 6;
+    pub const STRIKE: i32 = 1 << 7;
+    pub const ITALIC: i32 = 1 << 8;
+}
+
+/// A piece of text along with its [`TextFormatFlag`] bitmask
+#[derive(Clone, Debug)]
+pub struct FormattedText {
+    pub text: String,
+    pub format: i32,
+}
+
+impl FormattedText {
+    pub fn has_flag(&self, flag: i32) -> bool {
+        self.format & flag != 0
+    }
+}
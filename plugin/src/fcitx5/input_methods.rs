@@ -0,0 +1,59 @@
+//! Input methods and input method groups known to fcitx5
+
+use fcitx5_dbus::controller::ControllerProxyBlocking;
+use fcitx5_dbus::zbus::Result;
+
+/// An input method, as listed by fcitx5's controller
+#[derive(Clone, Debug)]
+pub struct InputMethodInfo {
+    /// Name used to refer to the input method, e.g. "pinyin" or "keyboard-us"
+    pub unique_name: String,
+    pub name: String,
+    pub native_name: String,
+    pub icon: String,
+    pub label: String,
+    pub language: String,
+}
+
+/// Input method groups, and the name of the current one
+pub fn input_method_groups(
+    controller: &ControllerProxyBlocking<'static>,
+) -> Result<(Vec<String>, String)> {
+    let groups = controller
+        .input_method_groups()?
+        .iter()
+        .map(|group| group.to_string())
+        .collect();
+    let current = controller.current_input_method_group()?.to_string();
+    Ok((groups, current))
+}
+
+/// Input methods of `group`, in the group's order
+pub fn group_input_methods(
+    controller: &ControllerProxyBlocking<'static>,
+    group: &str,
+) -> Result<Vec<InputMethodInfo>> {
+    let available = controller.available_input_methods()?;
+    let (_default_layout, items) = controller.input_method_group_info(group)?;
+
+    Ok(items
+        .iter()
+        .filter_map(|(unique_name, _layout)| {
+            available
+                .iter()
+                .find(|im| im.0.to_string() == unique_name.to_string())
+                .map(
+                    |(unique_name, name, native_name, icon, label, language, _)| {
+                        InputMethodInfo {
+                            unique_name: unique_name.to_string(),
+                            name: name.to_string(),
+                            native_name: native_name.to_string(),
+                            icon: icon.to_string(),
+                            label: label.to_string(),
+                            language: language.to_string(),
+                        }
+                    },
+                )
+        })
+        .collect())
+}
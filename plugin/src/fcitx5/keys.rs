@@ -0,0 +1,343 @@
+//! Translation of Neovim keys into fcitx5 key events
+
+use std::time::Instant;
+
+use fcitx5_dbus::input_context::InputContextProxyBlocking;
+use fcitx5_dbus::utils::key_event::{KeyState, KeyVal};
+use fcitx5_dbus::zbus::Result;
+
+/// X11 keysyms of the named keys of `:h key-notation`, names are lowercase
+const NAMED_KEYSYMS: &[(&str, u32)] = &[
+    ("bs", 0xff08),
+    ("backspace", 0xff08),
+    ("tab", 0xff09),
+    ("nl", 0xff0a),
+    ("cr", 0xff0d),
+    ("return", 0xff0d),
+    ("enter", 0xff0d),
+    ("esc", 0xff1b),
+    ("space", 0x20),
+    ("lt", 0x3c),
+    ("bslash", 0x5c),
+    ("bar", 0x7c),
+    ("del", 0xffff),
+    ("insert", 0xff63),
+    ("home", 0xff50),
+    ("left", 0xff51),
+    ("up", 0xff52),
+    ("right", 0xff53),
+    ("down", 0xff54),
+    ("pageup", 0xff55),
+    ("pagedown", 0xff56),
+    ("end", 0xff57),
+    // Keypad
+    ("kenter", 0xff8d),
+    ("khome", 0xff95),
+    ("kleft", 0xff96),
+    ("kup", 0xff97),
+    ("kright", 0xff98),
+    ("kdown", 0xff99),
+    ("kpageup", 0xff9a),
+    ("kpagedown", 0xff9b),
+    ("kend", 0xff9c),
+    ("korigin", 0xff9d),
+    ("kinsert", 0xff9e),
+    ("kdel", 0xff9f),
+    ("kmultiply", 0xffaa),
+    ("kplus", 0xffab),
+    ("kcomma", 0xffac),
+    ("kminus", 0xffad),
+    ("kpoint", 0xffae),
+    ("kdivide", 0xffaf),
+    ("kequal", 0xffbd),
+];
+
+/// X keycodes (evdev codes + 8) of the keys typing these characters on a US layout,
+/// starting from the given keycode
+const CHAR_KEYCODES: &[(&str, u32)] = &[
+    ("1234567890-=", 10),
+    ("!@#$%^&*()_+", 10),
+    ("qwertyuiop[]", 24),
+    ("QWERTYUIOP{}", 24),
+    ("asdfghjkl;'`", 38),
+    ("ASDFGHJKL:\"~", 38),
+    ("\\zxcvbnm,./", 51),
+    ("|ZXCVBNM<>?", 51),
+    (" ", 65),
+];
+
+/// X keycodes of keysyms that do not type a character
+const KEYSYM_KEYCODES: &[(u32, u32)] = &[
+    (0xff08, 22),
+    (0xff09, 23),
+    (0xff0a, 36),
+    (0xff0d, 36),
+    (0xff1b, 9),
+    (0xffff, 119),
+    (0xff63, 118),
+    (0xff50, 110),
+    (0xff51, 113),
+    (0xff52, 111),
+    (0xff53, 114),
+    (0xff54, 116),
+    (0xff55, 112),
+    (0xff56, 117),
+    (0xff57, 115),
+    (0xff8d, 104),
+    (0xff95, 79),
+    (0xff96, 83),
+    (0xff97, 80),
+    (0xff98, 85),
+    (0xff99, 88),
+    (0xff9a, 81),
+    (0xff9b, 89),
+    (0xff9c, 87),
+    (0xff9d, 84),
+    (0xff9e, 90),
+    (0xff9f, 91),
+    (0xffaa, 63),
+    (0xffab, 86),
+    (0xffad, 82),
+    (0xffae, 91),
+    (0xffaf, 106),
+    (0xffb0, 90),
+    (0xffb1, 87),
+    (0xffb2, 88),
+    (0xffb3, 89),
+    (0xffb4, 83),
+    (0xffb5, 84),
+    (0xffb6, 85),
+    (0xffb7, 79),
+    (0xffb8, 80),
+    (0xffb9, 81),
+    (0xffbd, 125),
+    (0xffc8, 95),
+    (0xffc9, 96),
+];
+
+/// Keysyms and X keycodes of the left modifier keys
+const MODIFIER_KEYS: &[(KeyState, u32, u32)] = &[
+    (KeyState::Shift, 0xffe1, 50),
+    (KeyState::Ctrl, 0xffe3, 37),
+    (KeyState::Alt, 0xffe9, 64),
+    (KeyState::Super, 0xffeb, 133),
+];
+
+const KEYSYM_F1: u32 = 0xffbe;
+const KEYSYM_KP_0: u32 = 0xffb0;
+const KEYSYM_BACKSPACE: u32 = 0xff08;
+
+lazy_static::lazy_static! {
+    static ref FIRST_KEY_EVENT: Instant = Instant::now();
+}
+
+/// A key press, as sent to fcitx5
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+    /// X11 keysym
+    pub sym: u32,
+    pub state: KeyState,
+}
+
+impl KeyEvent {
+    /// X keycode of the key on a US layout, 0 (unknown) for keys it does not have
+    fn keycode(&self) -> u32 {
+        if let Some((_, code)) =
+            KEYSYM_KEYCODES.iter().find(|(sym, _)| *sym == self.sym)
+        {
+            return *code;
+        }
+        if (KEYSYM_F1..KEYSYM_F1 + 10).contains(&self.sym) {
+            return 67 + self.sym - KEYSYM_F1;
+        }
+        char::from_u32(self.sym)
+            .and_then(|c| {
+                CHAR_KEYCODES.iter().find_map(|(chars, first)| {
+                    chars.chars().position(|k| k == c).map(|i| first + i as u32)
+                })
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Key value of an X11 keysym.  Keysyms of function keys lie in 0xff00..=0xffff, which are
+/// valid chars, so [`KeyVal::from_char`] passes them through as is.
+fn keysym(sym: u32) -> KeyVal {
+    KeyVal::from_char(char::from_u32(sym).unwrap_or('\0'))
+}
+
+/// Milliseconds since the first key event sent, fcitx5 only compares them with each other
+fn timestamp() -> u32 {
+    FIRST_KEY_EVENT.elapsed().as_millis() as u32
+}
+
+fn named_keysym(name: &str) -> Option<u32> {
+    let name = name.to_lowercase();
+    if let Some((_, sym)) = NAMED_KEYSYMS.iter().find(|(n, _)| *n == name) {
+        return Some(*sym);
+    }
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+        return (1..=35).contains(&n).then(|| KEYSYM_F1 + n - 1);
+    }
+    if let Some(n) = name.strip_prefix('k').and_then(|n| n.parse::<u32>().ok()) {
+        return (n <= 9).then(|| KEYSYM_KP_0 + n);
+    }
+    None
+}
+
+/// Key event of a typed character, uppercase letters are sent with Shift like a keyboard
+/// would
+pub fn translate_char(c: char) -> KeyEvent {
+    let state = if c.is_ascii_uppercase() {
+        KeyState::Shift
+    } else {
+        KeyState::NoState
+    };
+    KeyEvent {
+        sym: c as u32,
+        state,
+    }
+}
+
+/// Translate a key in Neovim's notation (`:h key-notation`), e.g. "<C-S-Home>", "<kPlus>"
+/// or "-", into the key fcitx5 expects.  `None` if the notation is not understood.  "<C-H>"
+/// and "<C-W>" are sent as Ctrl+Backspace.
+pub fn translate_key(notation: &str) -> Option<KeyEvent> {
+    let Some(inner) = notation
+        .strip_prefix('<')
+        .and_then(|rest| rest.strip_suffix('>'))
+        .filter(|inner| !inner.is_empty())
+    else {
+        let mut chars = notation.chars();
+        return match (chars.next(), chars.next()) {
+            // Control characters, like Neovim reports "<C-W>" as "\x17"
+            (Some(c), None)
+                if (1..=26).contains(&(c as u32)) && c != '\t' && c != '\r' =>
+            {
+                translate_key(&format!("<C-{}>", (b'a' + c as u8 - 1) as char))
+            }
+            (Some(c), None) => Some(translate_char(c)),
+            _ => None,
+        };
+    };
+
+    let mut state = KeyState::NoState;
+    let mut name = inner;
+    // The key itself may be "-", as in "<C-->"
+    while name.len() > 2 && name.as_bytes()[1] == b'-' {
+        state |= match name.as_bytes()[0].to_ascii_lowercase() {
+            b's' => KeyState::Shift,
+            b'c' => KeyState::Ctrl,
+            b'm' | b'a' => KeyState::Alt,
+            b'd' => KeyState::Super,
+            b't' => KeyState::Meta,
+            _ => return None,
+        };
+        name = &name[2..];
+    }
+
+    // Most terminals send ^H for Ctrl+Backspace, and engines delete a word of the preedit
+    // on Ctrl+Backspace like Neovim does on ^W
+    if state == KeyState::Ctrl
+        && (name.eq_ignore_ascii_case("h") || name.eq_ignore_ascii_case("w"))
+    {
+        return Some(KeyEvent {
+            sym: KEYSYM_BACKSPACE,
+            state,
+        });
+    }
+
+    let mut chars = name.chars();
+    let sym = match (chars.next(), chars.next()) {
+        (Some(c), None) => {
+            // "<C-A>" is the same as "<C-a>", but "<S-a>" types "A"
+            let c = if state.contains(KeyState::Shift) {
+                c.to_ascii_uppercase()
+            } else if state.contains(KeyState::Ctrl) {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            };
+            let key = translate_char(c);
+            return Some(KeyEvent {
+                state: state | key.state,
+                ..key
+            });
+        }
+        _ => named_keysym(name)?,
+    };
+    Some(KeyEvent { sym, state })
+}
+
+/// Send `key` to `ctx`, returns whether fcitx5 handled it.  With `send_release`, the key is
+/// sent like a keyboard would: modifiers pressed first, then the key pressed and released,
+/// then the modifiers released, all with X keycodes and timestamps.  Otherwise only the
+/// key press is sent, without keycode and timestamp.
+pub fn send_key(
+    ctx: &InputContextProxyBlocking,
+    key: KeyEvent,
+    send_release: bool,
+) -> Result<bool> {
+    if !send_release {
+        return ctx.process_key_event(keysym(key.sym), 0, key.state, false, 0);
+    }
+
+    let modifiers: Vec<_> = MODIFIER_KEYS
+        .iter()
+        .filter(|(modifier, _, _)| key.state.contains(*modifier))
+        .collect();
+    let mut state = KeyState::NoState;
+    for (modifier, sym, code) in &modifiers {
+        ctx.process_key_event(keysym(*sym), *code, state, false, timestamp())?;
+        state |= *modifier;
+    }
+
+    let code = key.keycode();
+    let accepted =
+        ctx.process_key_event(keysym(key.sym), code, key.state, false, timestamp())?;
+    ctx.process_key_event(keysym(key.sym), code, key.state, true, timestamp())?;
+
+    // A released modifier is still part of the state of its own release event
+    for (modifier, sym, code) in modifiers.iter().rev() {
+        ctx.process_key_event(keysym(*sym), *code, state, true, timestamp())?;
+        state.remove(*modifier);
+    }
+    Ok(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(notation: &str) -> (u32, KeyState) {
+        let key = translate_key(notation).expect(notation);
+        (key.sym, key.state)
+    }
+
+    #[test]
+    fn translates_modified_keys() {
+        assert_eq!(key("<C-W>"), (KEYSYM_BACKSPACE, KeyState::Ctrl));
+        assert_eq!(key("<S-Tab>"), (0xff09, KeyState::Shift));
+        assert_eq!(key("<C-->"), ('-' as u32, KeyState::Ctrl));
+    }
+
+    #[test]
+    fn translates_named_keys() {
+        assert_eq!(key("<kPlus>"), (0xffab, KeyState::NoState));
+        assert_eq!(key("<F12>"), (0xffc9, KeyState::NoState));
+        assert_eq!(key("<lt>"), ('<' as u32, KeyState::NoState));
+        assert_eq!(translate_key("<F12>").unwrap().keycode(), 96);
+    }
+
+    #[test]
+    fn translates_typed_characters() {
+        assert_eq!(key("A"), ('A' as u32, KeyState::Shift));
+        assert_eq!(translate_key("A").unwrap().keycode(), 38);
+        assert_eq!(key("\x17"), (KEYSYM_BACKSPACE, KeyState::Ctrl));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(translate_key("<NoSuchKey>").is_none());
+    }
+}
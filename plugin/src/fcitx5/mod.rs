@@ -2,3 +2,7 @@
 
 pub mod candidates;
 pub mod connection;
+pub mod format;
+pub mod input_methods;
+pub mod keys;
+pub mod watcher;
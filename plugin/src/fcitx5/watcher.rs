@@ -0,0 +1,50 @@
+//! Watch the Fcitx5 service on the session bus
+
+use fcitx5_dbus::zbus::{
+    blocking::{fdo::DBusProxy, Connection},
+    Result,
+};
+use nvim_oxi::libuv::AsyncHandle;
+
+use crate::plugin::PLUGIN_NAME;
+
+/// Well-known bus name owned by a running Fcitx5 instance
+pub static FCITX5_SERVICE: &str = "org.fcitx.Fcitx5";
+
+/// Spawn a thread that fires `trigger` every time [`FCITX5_SERVICE`] gets a new owner,
+/// i.e. whenever Fcitx5 (re)starts.
+pub fn watch_fcitx5_restart(trigger: AsyncHandle) -> Result<()> {
+    let conn = Connection::session()?;
+    let dbus: DBusProxy<'static> = DBusProxy::new(&conn)?;
+
+    std::thread::spawn(move || {
+        match dbus.receive_name_owner_changed_with_args(&[(0, FCITX5_SERVICE)]) {
+            Ok(name_owner_changed) => {
+                for signal in name_owner_changed {
+                    match signal.args() {
+                        // An empty new owner means Fcitx5 went away, wait until it is
+                        // back
+                        Ok(args) if args.new_owner().is_some() => {
+                            let _ = trigger.send();
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!(
+                                "{}: Error processing NameOwnerChanged signal: {}",
+                                PLUGIN_NAME, e,
+                            );
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: Failed to watch {} on the session bus: {}",
+                    PLUGIN_NAME, FCITX5_SERVICE, e,
+                );
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -15,5 +15,45 @@ fn fcitx5_ui_rs() -> Dictionary {
     let mut dict = // Dictionary::new();
     Dictionary::from_iter([("setup", Function::from_fn(neovim::functions::setup))]);
     dict.insert("get_im", Function::from_fn(neovim::functions::get_im));
+    dict.insert(
+        "on_im_change",
+        Function::from_fn(neovim::functions::on_im_change),
+    );
+    dict.insert("on_commit", Function::from_fn(neovim::functions::on_commit));
+    dict.insert(
+        "on_preedit_change",
+        Function::from_fn(neovim::functions::on_preedit_change),
+    );
+    dict.insert(
+        "on_candidates",
+        Function::from_fn(neovim::functions::on_candidates),
+    );
+    dict.insert(
+        "list_input_methods",
+        Function::from_fn(neovim::functions::list_input_methods),
+    );
+    dict.insert(
+        "list_input_method_groups",
+        Function::from_fn(neovim::functions::list_input_method_groups),
+    );
+    dict.insert("set_im", Function::from_fn(neovim::functions::set_im));
+    dict.insert(
+        "switch_im_group",
+        Function::from_fn(neovim::functions::switch_im_group),
+    );
+    dict.insert(
+        "select_candidate",
+        Function::from_fn(neovim::functions::select_candidate),
+    );
+    dict.insert("next_page", Function::from_fn(neovim::functions::next_page));
+    dict.insert("prev_page", Function::from_fn(neovim::functions::prev_page));
+    dict.insert(
+        "next_candidate",
+        Function::from_fn(neovim::functions::next_candidate),
+    );
+    dict.insert(
+        "prev_candidate",
+        Function::from_fn(neovim::functions::prev_candidate),
+    );
     dict
 }
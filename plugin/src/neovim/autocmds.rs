@@ -4,15 +4,29 @@ use nvim_oxi::{
     self as oxi,
     api::{
         self,
-        opts::{CreateAugroupOpts, CreateAutocmdOpts},
+        opts::{CreateAugroupOpts, CreateAutocmdOpts, OptionOpts},
+        types::AutocmdCallbackArgs,
         Buffer,
     },
     libuv::AsyncHandle,
     Error as OxiError,
 };
 
-use crate::plugin::{get_state, Fcitx5Plugin};
-use crate::{ignore_dbus_no_interface_error, plugin::get_im_window_state};
+use crate::fcitx5::keys::{send_key, translate_char};
+use crate::neovim::{
+    commands::{
+        connect_context, destroy_all_contexts, forget_buffer, load_plugin,
+        process_im_window_updates,
+    },
+    context_aware::cursor_in_context,
+    rules::{apply_insert_im, apply_rule_im},
+};
+use crate::plugin::{
+    config::{AutoLoad, ContextScope},
+    get_state, ContextKey, Fcitx5Plugin,
+};
+use crate::utils::in_insert_mode;
+use crate::{ignore_dbus_no_interface_error, lock_logged, plugin::get_im_window_state};
 use std::sync::{Arc, Mutex};
 
 /// Setup autocommands for input method switching
@@ -35,16 +49,10 @@ pub fn register_autocommands(
     )?;
     state_guard.augroup_id.insert(buf.handle(), augroup_id);
 
-    // Ensure we have controller and ctx
-    let ctx = state_guard
-        .ctx
-        .get(&buf.handle())
-        .expect("Input context not initialized"); // FIXME: we probably do not want to panic here
-
     let opts = CreateAutocmdOpts::builder()
         .group(augroup_id)
         .buffer(buf.clone())
-        .desc("Switch to Pinyin input method when entering insert mode")
+        .desc("Switch to the buffer's input method when entering insert mode")
         .callback({
             let state_ref = state.clone();
             let buf = buf.clone();
@@ -54,17 +62,63 @@ pub fn register_autocommands(
                     return Ok(false);
                 }
 
-                let state_guard = state_ref.lock().unwrap();
+                let mut state_guard = state_ref.lock().unwrap();
                 if !state_guard.initialized(&buf) {
                     return Ok(false);
                 }
-                ignore_dbus_no_interface_error!(state_guard.activate_im(&buf));
+                state_guard.set_active_context(&buf);
+                let key = state_guard.context_key(&buf);
+                ignore_dbus_no_interface_error!(state_guard.set_focus(Some(key)));
+                ignore_dbus_no_interface_error!(apply_insert_im(
+                    &mut state_guard,
+                    &buf
+                ));
                 Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
             }
         })
         .build();
     api::create_autocmd(["InsertEnter"], &opts)?;
 
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .buffer(buf.clone())
+        .desc("Switch input method when moving into or out of comments and strings")
+        .callback({
+            let state_ref = state.clone();
+            let buf = buf.clone();
+            move |_| {
+                let mut state_guard = state_ref.lock().unwrap();
+                if !state_guard.initialized(&buf) {
+                    return Ok(false);
+                }
+                // Moving while composing would abandon the preedit
+                if lock_logged!(state_guard.im_window_state, "IMWindowState")
+                    .is_composing()
+                {
+                    return Ok(false);
+                }
+                let Some(in_context) = cursor_in_context(&state_guard, &buf) else {
+                    return Ok(false);
+                };
+                if state_guard.in_context.insert(buf.handle(), in_context)
+                    == Some(in_context)
+                {
+                    return Ok(false);
+                }
+                if in_context {
+                    ignore_dbus_no_interface_error!(apply_rule_im(
+                        &mut state_guard,
+                        &buf
+                    ));
+                } else {
+                    ignore_dbus_no_interface_error!(state_guard.deactivate_im(&buf));
+                }
+                Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+            }
+        })
+        .build();
+    api::create_autocmd(["CursorMovedI"], &opts)?;
+
     let opts = CreateAutocmdOpts::builder()
         .group(augroup_id)
         .buffer(buf.clone())
@@ -73,11 +127,17 @@ pub fn register_autocommands(
             let state_ref = state.clone();
             let buf = buf.clone();
             move |_| {
-                let state_guard = state_ref.lock().unwrap();
+                let mut state_guard = state_ref.lock().unwrap();
                 if !state_guard.initialized(&buf) {
                     return Ok(false);
                 }
                 ignore_dbus_no_interface_error!(state_guard.deactivate_im(&buf));
+                ignore_dbus_no_interface_error!(state_guard.set_focus(None));
+                // Characters held back behind a commit go in before normal mode keys
+                let im_window_state = state_guard.im_window_state.clone();
+                drop(state_guard);
+                lock_logged!(im_window_state, "IMWindowState").flush_held_chars();
+                process_im_window_updates(im_window_state)?;
                 Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
             }
         })
@@ -90,21 +150,68 @@ pub fn register_autocommands(
         .group(augroup_id)
         .desc("Reset input context when leaving window or buffer")
         .callback({
-            let ctx_clone = ctx.clone();
             let state_ref = state.clone();
             let buf = buf.clone();
-            move |_| {
-                let state_guard = state_ref.lock().unwrap();
+            move |args: AutocmdCallbackArgs| {
+                let mut state_guard = state_ref.lock().unwrap();
                 if !state_guard.initialized(&buf) {
                     return Ok(false);
                 }
-                ignore_dbus_no_interface_error!(ctx_clone.reset());
+                // A window's own input context keeps its composition while another window
+                // is focused
+                if state_guard.context_scope() != ContextScope::Window
+                    || args.event != "WinLeave"
+                {
+                    ignore_dbus_no_interface_error!(state_guard.reset_im_ctx(&buf));
+                }
+                ignore_dbus_no_interface_error!(state_guard.set_focus(None));
+                // Hide the composition right away, updates from the reset context are
+                // dropped from now on
+                if state_guard.leave_context(&buf) {
+                    let im_window_state = state_guard.im_window_state.clone();
+                    drop(state_guard);
+                    process_im_window_updates(im_window_state)?;
+                }
                 Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
             }
         })
         .build();
     api::create_autocmd(["WinLeave", "BufLeave"], &opts)?;
 
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .buffer(buf.clone())
+        .desc("Focus the input context of the window entered")
+        .callback({
+            let state_ref = state.clone();
+            let buf = buf.clone();
+            let trigger = trigger.clone();
+            move |_| {
+                let mut state_guard = state_ref.lock().unwrap();
+                if !state_guard.loaded_buffers.contains(&buf.handle()) {
+                    return Ok(false);
+                }
+                let key = state_guard.context_key(&buf);
+                if state_guard.context_scope() == ContextScope::Window {
+                    // Windows split off or switched to this buffer have no input context
+                    // yet
+                    if !connect_context(&mut state_guard, key, &trigger)? {
+                        return Ok(false);
+                    }
+                    // Show the composition of this window's context, fcitx5 sends it
+                    // again on focus, and the IM window is placed at this window's cursor
+                    state_guard.set_active_context(&buf);
+                }
+                // Insert mode carries over when the window is switched from a mapping
+                if in_insert_mode() {
+                    ignore_dbus_no_interface_error!(state_guard.set_focus(Some(key)));
+                }
+                Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+            }
+        })
+        .build();
+    api::create_autocmd(["WinEnter", "BufEnter"], &opts)?;
+
     // Release the lock before setting up InsertCharPre autocmd
     drop(state_guard);
 
@@ -114,6 +221,141 @@ pub fn register_autocommands(
     Ok(())
 }
 
+/// Track whether Neovim has the focus of the terminal, and is not suspended, so that
+/// fcitx5 is told when our input contexts lose and regain the focus
+pub fn register_focus_tracking() -> oxi::Result<()> {
+    let augroup_id = api::create_augroup(
+        "fcitx5-ui-rs-nvim-focus",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .desc("Focus in and out of the input context with Neovim")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let state = get_state();
+            let mut state_guard = state.lock().unwrap();
+            state_guard.ui_focused =
+                matches!(args.event.as_str(), "FocusGained" | "VimResume");
+            let buf = api::get_current_buf();
+            let key = (in_insert_mode() && state_guard.initialized(&buf))
+                .then(|| state_guard.context_key(&buf));
+            ignore_dbus_no_interface_error!(state_guard.set_focus(key));
+            Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+        })
+        .build();
+    api::create_autocmd(
+        ["FocusGained", "FocusLost", "VimSuspend", "VimResume"],
+        &opts,
+    )?;
+
+    Ok(())
+}
+
+/// Tear down the state of buffers and windows that go away, and every input context when
+/// Neovim exits
+pub fn register_lifecycle_autocmds() -> oxi::Result<()> {
+    let augroup_id = api::create_augroup(
+        "fcitx5-ui-rs-nvim-lifecycle",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .desc("Forget the state of a deleted or wiped out buffer")
+        .callback(move |args: AutocmdCallbackArgs| {
+            // Deleted buffers lose their mappings and buffer-local autocommands too
+            forget_buffer(get_state(), &args.buffer)?;
+            Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+        })
+        .build();
+    api::create_autocmd(["BufDelete", "BufWipeout"], &opts)?;
+
+    // Not buffer-local, the closed window may show a buffer the plugin is not loaded in
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .desc("Destroy the input context of a closed window")
+        .callback(move |args: AutocmdCallbackArgs| {
+            // The pattern of WinClosed is the closed window's ID
+            if let Ok(handle) = args.r#match.parse::<i32>() {
+                let state = get_state();
+                let mut state_guard = state.lock().unwrap();
+                state_guard.drop_context(&ContextKey::Window(handle));
+            }
+            Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+        })
+        .build();
+    api::create_autocmd(["WinClosed"], &opts)?;
+
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .desc("Destroy all input contexts before exiting")
+        .callback(move |_| {
+            destroy_all_contexts(get_state());
+            Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+        })
+        .build();
+    api::create_autocmd(["VimLeavePre"], &opts)?;
+
+    Ok(())
+}
+
+/// Load the plugin in the buffers that are entered, or whose filetype is set, as allowed by
+/// the `auto_load` config
+pub fn register_auto_load(config: &AutoLoad) -> oxi::Result<()> {
+    let augroup_id = api::create_augroup(
+        "fcitx5-ui-rs-nvim-auto-load",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let opts = CreateAutocmdOpts::builder()
+        .group(augroup_id)
+        .desc("Load the plugin according to the auto_load config")
+        .callback({
+            let config = config.clone();
+            move |args: AutocmdCallbackArgs| {
+                // FileType also fires for buffers that are not displayed
+                let buf = args.buffer;
+                if buf != api::get_current_buf() || !should_auto_load(&config, &buf) {
+                    return Ok(false);
+                }
+                load_plugin(get_state(), &buf)?;
+                Ok::<_, OxiError>(false) // NB: return false to keep this autocmd
+            }
+        })
+        .build();
+    api::create_autocmd(["BufEnter", "FileType"], &opts)?;
+
+    Ok(())
+}
+
+fn should_auto_load(config: &AutoLoad, buf: &Buffer) -> bool {
+    {
+        let state = get_state();
+        let state_guard = state.lock().unwrap();
+        if state_guard.initialized(buf)
+            || state_guard.unloaded_buffers.contains(&buf.handle())
+        {
+            return false;
+        }
+    }
+
+    let option = |name: &str| {
+        api::get_option_value::<String>(
+            name,
+            &OptionOpts::builder().buffer(buf.clone()).build(),
+        )
+        .unwrap_or_default()
+    };
+    let filetype = option("filetype");
+    !config.exclude_buftypes.contains(&option("buftype"))
+        && !config.exclude_filetypes.contains(&filetype)
+        && (config.filetypes.is_empty() || config.filetypes.contains(&filetype))
+}
+
 pub fn deregister_autocommands(
     state: Arc<Mutex<Fcitx5Plugin>>,
     buf: &Buffer,
@@ -141,7 +383,6 @@ pub fn setup_insert_char_pre(trigger: AsyncHandle, buf: &Buffer) -> oxi::Result<
         .get(&buf.handle())
         .expect("Augroup should be initialized")
         .to_owned();
-    let ctx_clone = state_guard.ctx.get(&buf.handle()).unwrap().clone();
 
     // Drop lock before creating autocmd
     drop(state_guard);
@@ -149,6 +390,9 @@ pub fn setup_insert_char_pre(trigger: AsyncHandle, buf: &Buffer) -> oxi::Result<
     // Get a reference to the candidate state
     let im_window_state = get_im_window_state();
 
+    // Look up the input context on every key, it is recreated when Fcitx5 restarts
+    let callback_buf = buf.clone();
+
     let opts = CreateAutocmdOpts::builder()
         .buffer(buf.clone())
         .group(augroup_id)
@@ -166,26 +410,62 @@ pub fn setup_insert_char_pre(trigger: AsyncHandle, buf: &Buffer) -> oxi::Result<
                 return Ok(false);
             }
 
-            // Clone state for use inside callback
-            let im_window_state_clone = im_window_state.clone();
-            let mut guard = im_window_state_clone.lock().unwrap();
+            let (ctx_clone, send_release, context) = {
+                let state = get_state();
+                let state_guard = state.lock().unwrap();
+                match state_guard.get_ctx(&callback_buf) {
+                    Some(ctx) => {
+                        state_guard.set_active_context(&callback_buf);
+                        (
+                            ctx.clone(),
+                            state_guard.key_options().send_release,
+                            state_guard.context_key(&callback_buf),
+                        )
+                    }
+                    None => return Ok(false),
+                }
+            };
 
             // Get the first character (should be only one)
             let c = char_arg.chars().next().unwrap();
 
-            // Send key to Fcitx5
-            let code = fcitx5_dbus::utils::key_event::KeyVal::from_char(c);
-            let state = fcitx5_dbus::utils::key_event::KeyState::NoState;
+            // A held back character typed again, fcitx5 has seen it already
+            {
+                let mut guard = im_window_state.lock().unwrap();
+                if guard.fed_chars.front() == Some(&c) {
+                    guard.fed_chars.pop_front();
+                    return Ok(false);
+                }
+            }
+
+            // The indicator shown after switching input methods is not a composition
+            let composing = {
+                let guard = im_window_state.lock().unwrap();
+                guard.is_composing() && !guard.is_showing_current_im()
+            };
 
             // Process the key in Fcitx5
-            if let Ok(accept) = ctx_clone.process_key_event(code, 0, state, false, 0) {
-                if accept {
-                    api::set_vvar("char", "")?;
+            match send_key(&ctx_clone, translate_char(c), send_release) {
+                Ok(true) => api::set_vvar("char", "")?,
+                Ok(false) => {
+                    let mut guard = im_window_state.lock().unwrap();
+                    // The key ends the composition: fcitx5 commits it before answering,
+                    // but the commit reaches us asynchronously
+                    if composing {
+                        guard.awaiting_commit = Some(context);
+                    }
+                    // Hold the character back and queue it after the pending commits, so
+                    // that text appears in the order fcitx5 produced it
+                    if guard.must_hold_char() {
+                        api::set_vvar("char", "")?;
+                        guard.hold_char(c, context);
+                    }
                 }
+                Err(_) => {}
             }
 
-            // After processing key:
-            guard.mark_for_update(); // Mark that content needs updating
+            // After processing key, mark that content needs updating
+            im_window_state.lock().unwrap().mark_for_update();
 
             // Schedule an update on main thread
             trigger.send()?;
@@ -4,8 +4,14 @@ use std::sync::{Arc, Mutex};
 
 use nvim_oxi::{
     self as oxi,
-    api::{self, opts::CreateCommandOpts, Buffer},
+    api::{
+        self,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, CommandComplete, CommandNArgs},
+        Buffer, Window,
+    },
     libuv::AsyncHandle,
+    Array, Function, Object,
 };
 
 use crate::utils::as_api_error;
@@ -18,12 +24,50 @@ use crate::{
     fcitx5::candidates::IMWindowState, neovim::autocmds::register_autocommands,
 };
 use crate::{
-    fcitx5::{candidates::UpdateType, connection::prepare},
-    plugin::Fcitx5Plugin,
+    fcitx5::{
+        candidates::UpdateType, connection::prepare, watcher::watch_fcitx5_restart,
+    },
+    plugin::{ContextKey, Fcitx5Plugin},
+};
+use crate::{
+    lock_logged,
+    plugin::get_state,
+    utils::{do_feedkeys_noremap, in_insert_mode},
 };
-use crate::{lock_logged, plugin::get_state, utils::do_feedkeys_noremap};
 
-use super::{autocmds::deregister_autocommands, keymaps::register_keymaps};
+use super::{
+    autocmds::deregister_autocommands,
+    events::schedule_events,
+    keymaps::{deregister_keymaps, register_keymaps},
+    preedit::render_inline_preedit,
+    rules::apply_insert_im,
+};
+
+/// Pick an input method of the current group for window `_A[1]` showing buffer `_A[2]`
+/// with `vim.ui.select`, so that frontends overriding it (telescope, fzf-lua,
+/// dressing.nvim, ...) are used.  The callback may run after the picker changed windows,
+/// so it switches in the window the picker was opened from.
+const SELECT_INPUT_METHOD: &str = r#"(function(win, buf)
+  local fcitx5 = require("fcitx5_ui_rs")
+  local current = fcitx5.get_im()
+  vim.ui.select(fcitx5.list_input_methods(), {
+    prompt = "Input method",
+    format_item = function(im)
+      local marker = im.unique_name == current and "* " or "  "
+      return marker .. im.name .. " (" .. im.unique_name .. ")"
+    end,
+  }, function(im)
+    if im == nil
+      or not vim.api.nvim_win_is_valid(win)
+      or vim.api.nvim_win_get_buf(win) ~= buf
+    then
+      return
+    end
+    vim.api.nvim_win_call(win, function()
+      fcitx5.set_im(im.unique_name)
+    end)
+  end)
+end)(_A[1], _A[2])"#;
 
 /// Register all plugin commands
 pub fn register_commands() -> oxi::Result<()> {
@@ -60,7 +104,7 @@ pub fn register_commands() -> oxi::Result<()> {
         {
             let state = state.clone();
             move |_| {
-                let state_guard = state.lock().unwrap();
+                let mut state_guard = state.lock().unwrap();
                 let buf = api::get_current_buf();
                 if !state_guard.initialized(&buf) {
                     oxi::print!(
@@ -71,7 +115,7 @@ pub fn register_commands() -> oxi::Result<()> {
 
                 ignore_dbus_no_interface_error!(state_guard.toggle_im(&buf));
 
-                oxi::print!("{}", state_guard.get_im(&buf).map_err(as_api_error)?);
+                oxi::print!("{}", state_guard.get_im(&buf)?.name);
 
                 Ok::<_, oxi::Error>(())
             }
@@ -86,7 +130,7 @@ pub fn register_commands() -> oxi::Result<()> {
         {
             let state = state.clone();
             move |_| {
-                let state_guard = state.lock().unwrap();
+                let mut state_guard = state.lock().unwrap();
                 let buf = api::get_current_buf();
                 if !state_guard.initialized(&buf) {
                     oxi::print!(
@@ -107,7 +151,7 @@ pub fn register_commands() -> oxi::Result<()> {
         {
             let state = state.clone();
             move |_| {
-                let state_guard = state.lock().unwrap();
+                let mut state_guard = state.lock().unwrap();
                 let buf = api::get_current_buf();
                 if !state_guard.initialized(&buf) {
                     oxi::print!(
@@ -123,96 +167,339 @@ pub fn register_commands() -> oxi::Result<()> {
         &CreateCommandOpts::default(),
     )?;
 
+    api::create_user_command(
+        "Fcitx5IMSet",
+        {
+            let state = state.clone();
+            move |args: CommandArgs| {
+                let mut state_guard = state.lock().unwrap();
+                let buf = api::get_current_buf();
+                if !state_guard.initialized(&buf) {
+                    oxi::print!(
+                        "{PLUGIN_NAME}: not loaded. Run :Fcitx5PluginLoad first"
+                    );
+                    return Ok(());
+                }
+
+                let name = args.args.unwrap_or_default();
+                ignore_dbus_no_interface_error!(
+                    state_guard.set_im_checked(&buf, name.trim())
+                );
+                Ok::<_, oxi::Error>(())
+            }
+        },
+        &CreateCommandOpts::builder()
+            .desc("Switch to an input method of the current group")
+            .nargs(CommandNArgs::One)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    let state = get_state();
+                    let state_guard = state.lock().unwrap();
+                    let names = state_guard
+                        .list_input_methods(&api::get_current_buf())
+                        .map(|ims| ims.into_iter().map(|im| im.unique_name).collect())
+                        .unwrap_or_default();
+                    complete_from(names, &arg_lead)
+                },
+            )))
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "Fcitx5IMSelect",
+        {
+            let state = state.clone();
+            move |_| {
+                let state_guard = state.lock().unwrap();
+                let buf = api::get_current_buf();
+                if !state_guard.initialized(&buf) {
+                    oxi::print!(
+                        "{PLUGIN_NAME}: not loaded. Run :Fcitx5PluginLoad first"
+                    );
+                    return Ok(());
+                }
+                drop(state_guard);
+
+                let win = api::get_current_win();
+                api::call_function::<_, Object>(
+                    "luaeval",
+                    (
+                        SELECT_INPUT_METHOD,
+                        Array::from_iter([win.handle(), buf.handle()]),
+                    ),
+                )?;
+                Ok::<_, oxi::Error>(())
+            }
+        },
+        &CreateCommandOpts::builder()
+            .desc("Pick an input method of the current group with vim.ui.select")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "Fcitx5IMGroup",
+        {
+            let state = state.clone();
+            move |args: CommandArgs| {
+                let state_guard = state.lock().unwrap();
+                let group = args.args.unwrap_or_default();
+                ignore_dbus_no_interface_error!(
+                    state_guard.switch_im_group(&api::get_current_buf(), group.trim())
+                );
+                Ok::<_, oxi::Error>(())
+            }
+        },
+        &CreateCommandOpts::builder()
+            .desc("Switch input method group")
+            .nargs(CommandNArgs::One)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| {
+                    let state = get_state();
+                    let state_guard = state.lock().unwrap();
+                    let groups = state_guard
+                        .list_input_method_groups(&api::get_current_buf())
+                        .map(|(groups, _)| groups)
+                        .unwrap_or_default();
+                    complete_from(groups, &arg_lead)
+                },
+            )))
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "Fcitx5IMList",
+        {
+            let state = state.clone();
+            move |_| {
+                let state_guard = state.lock().unwrap();
+                let buf = api::get_current_buf();
+                let (_, current_group) = state_guard.list_input_method_groups(&buf)?;
+                let current_im = state_guard.get_im(&buf).ok().map(|im| im.name);
+                let mut lines = vec![format!("Input method group: {current_group}")];
+                for im in state_guard.list_input_methods(&buf)? {
+                    let marker =
+                        if current_im.as_deref() == Some(im.unique_name.as_str()) {
+                            "*"
+                        } else {
+                            " "
+                        };
+                    lines.push(format!(
+                        "{marker} {} ({}, {})",
+                        im.unique_name, im.name, im.language
+                    ));
+                }
+                oxi::print!("{}", lines.join("\n"));
+                Ok::<_, oxi::Error>(())
+            }
+        },
+        &CreateCommandOpts::builder()
+            .desc("List the input methods of the current group")
+            .build(),
+    )?;
+
     Ok(())
 }
 
+/// Completion candidates that start with the argument being typed
+fn complete_from(candidates: Vec<String>, arg_lead: &str) -> Vec<String> {
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(arg_lead))
+        .collect()
+}
+
+/// Window to insert a commit of the input context keyed by `context` in: the current
+/// window if it shows a buffer served by that context, otherwise a window showing the
+/// context's buffer, or the context's own window.  `None` when that buffer or window is
+/// not displayed anymore.
+fn commit_target_window(context: ContextKey) -> Option<Window> {
+    let current = api::get_current_win();
+    match context {
+        ContextKey::Shared => Some(current),
+        ContextKey::Buffer(handle) if api::get_current_buf().handle() == handle => {
+            Some(current)
+        }
+        ContextKey::Buffer(handle) => {
+            let winid = api::call_function::<_, i32>("bufwinid", (handle,)).ok()?;
+            (winid > 0).then(|| Window::from(winid))
+        }
+        ContextKey::Window(handle) => {
+            Some(Window::from(handle)).filter(Window::is_valid)
+        }
+    }
+}
+
+/// Insert `text` at the cursor of `win` and move the cursor past it
+fn insert_at_cursor(mut win: Window, text: &str) {
+    let is_current = win == api::get_current_win();
+    let Ok(mut buf) = win.get_buf() else {
+        return;
+    };
+    if let Ok((row, col)) = win.get_cursor() {
+        // Convert to 0-indexed for the API
+        let row_idx = row - 1;
+        // Insert text at cursor position
+        let _ = buf.set_text(
+            row_idx..row_idx,      // Only modify the current line
+            col,                   // Start column
+            col, // End column (same as start to insert without replacing)
+            vec![text.to_owned()], // Text to insert as a Vec<String>
+        );
+        // Move cursor to end of inserted text
+        let _ = win.set_cursor(row, col + text.len());
+        // NB: Force undo break
+        // TODO: Maybe make this configurable from lua side
+        // REF: `:h i_CTRL-G_u`
+        if is_current {
+            let _ = do_feedkeys_noremap("<C-g>u");
+        }
+    }
+}
+
 pub fn process_im_window_updates(
     im_window_state_arc: Arc<Mutex<IMWindowState>>,
 ) -> oxi::Result<()> {
     let mut guard = lock_logged!(im_window_state_arc, "IMWindowState");
+    let mut events = Vec::new();
 
     while let Some(update_type) = guard.pop_update() {
         match update_type {
             UpdateType::UpdateContent => {
+                if guard.config.inline_preedit {
+                    render_inline_preedit(
+                        guard.preedit_segments.clone(),
+                        guard.preedit_cursor,
+                        guard.config.window.cursor.clone(),
+                    );
+                }
                 let plan = guard.build_render_plan();
                 if let Some(buffer) = guard.buffer.as_ref() {
                     IMWindowState::apply_render_plan_to_buffer(buffer, &plan);
                 }
                 guard.display_window_from_plan(&plan)?;
             }
-            UpdateType::Insert(s) => {
+            UpdateType::Insert { text: s, context } => {
                 // The commit_string handler in fcitx5/candidates.rs, which calls
                 // mark_for_insert, relies on a subsequent update_client_side_ui signal
                 // from fcitx5 to clear preedit/candidates and trigger a Hide action.
                 // So, Insert itself doesn't directly hide the window.
+                guard.inserts_in_flight += 1;
+                let im_window_state = im_window_state_arc.clone();
+                oxi::schedule(move |_| {
+                    // The commit belongs to the buffer whose input context produced it,
+                    // which may not be the current one anymore.  Drop it if that buffer
+                    // is gone from the screen.
+                    if let Some(win) = commit_target_window(context) {
+                        insert_at_cursor(win, &s);
+                    }
+                    let mut guard = lock_logged!(im_window_state, "IMWindowState");
+                    guard.inserts_in_flight -= 1;
+                });
+            }
+            UpdateType::Feed { c, context } => {
+                guard.inserts_in_flight += 1;
+                let im_window_state = im_window_state_arc.clone();
                 oxi::schedule(move |_| {
-                    // Insert text directly at cursor position
-                    let mut win = api::get_current_win();
-                    let mut buf = api::get_current_buf();
-                    if let Ok((row, col)) = win.get_cursor() {
-                        // Convert to 0-indexed for the API
-                        let row_idx = row - 1;
-                        // Insert text at cursor position
-                        let _ = buf.set_text(
-                            row_idx..row_idx, // Only modify the current line
-                            col,              // Start column
-                            col, // End column (same as start to insert without replacing)
-                            vec![s.clone()], // Text to insert as a Vec<String>
+                    let mut guard = lock_logged!(im_window_state, "IMWindowState");
+                    guard.inserts_in_flight -= 1;
+                    let Some(win) = commit_target_window(context) else {
+                        return;
+                    };
+                    if win == api::get_current_win() && in_insert_mode() {
+                        // Type it again, so that abbreviations, 'textwidth' and undo
+                        // treat it like any other typed character
+                        guard.fed_chars.push_back(c);
+                        drop(guard);
+                        let _ = api::call_function::<_, Object>(
+                            "nvim_feedkeys",
+                            (c.to_string(), "n", true),
                         );
-                        // Move cursor to end of inserted text
-                        let _ = win.set_cursor(row, col + s.len());
-                        // NB: Force undo break
-                        // TODO: Maybe make this configurable from lua side
-                        // REF: `:h i_CTRL-G_u`
-                        let _ = do_feedkeys_noremap("<C-g>u");
+                    } else {
+                        drop(guard);
+                        insert_at_cursor(win, &c.to_string());
                     }
                 });
             }
+            UpdateType::Notify(event) => events.push(event),
         }
     }
 
+    // Callbacks may call back into the plugin, so they must not run under our lock
+    drop(guard);
+    schedule_events(events);
+
     Ok(())
 }
 
-/// Initialize the connection and input context for current buffer
-pub fn load_plugin(state: Arc<Mutex<Fcitx5Plugin>>, buf: &Buffer) -> oxi::Result<()> {
-    let mut state_guard = state.lock().unwrap();
-
-    if state_guard.initialized(buf) {
-        oxi::print!("{PLUGIN_NAME}: already loaded");
-        return Ok(());
+/// Connect an input context keyed by `key` unless there already is one, returns whether
+/// it is connected
+pub fn connect_context(
+    state_guard: &mut Fcitx5Plugin,
+    key: ContextKey,
+    trigger: &AsyncHandle,
+) -> oxi::Result<bool> {
+    if state_guard.ctx.contains_key(&key) {
+        return Ok(true);
     }
 
     // Initialize the connection
     let (controller, ctx) = if let Ok(Some(pair)) = prepare().map_err(as_api_error) {
         pair
     } else {
-        oxi::print!("{PLUGIN_NAME}: failed to connect to DBus");
-        return Ok(());
+        return Ok(false);
     };
 
     // Get a reference to the candidate state for setup
     let im_window_state = state_guard.im_window_state.clone();
 
     // Store in state
-    state_guard
-        .controller
-        .insert(buf.handle(), controller.clone());
-    state_guard.ctx.insert(buf.handle(), ctx.clone());
-    ignore_dbus_no_interface_error!(state_guard.deactivate_im(buf));
-
-    let trigger =
-        AsyncHandle::new(move || process_im_window_updates(get_im_window_state()))?;
+    state_guard.controller.insert(key, controller);
+    state_guard.ctx.insert(key, ctx.clone());
 
     // Setup candidate receivers
-    setup_im_window_receivers(&ctx, im_window_state, trigger.clone())
+    setup_im_window_receivers(&ctx, key, im_window_state, trigger.clone())
         .map_err(as_api_error)?;
+    Ok(true)
+}
+
+/// Initialize the connection and input context for current buffer
+pub fn load_plugin(state: Arc<Mutex<Fcitx5Plugin>>, buf: &Buffer) -> oxi::Result<()> {
+    let mut state_guard = state.lock().unwrap();
+
+    if state_guard.initialized(buf) {
+        oxi::print!("{PLUGIN_NAME}: already loaded");
+        return Ok(());
+    }
+
+    let trigger = match state_guard.trigger.clone() {
+        Some(trigger) => trigger,
+        None => {
+            let trigger = AsyncHandle::new(move || {
+                process_im_window_updates(get_im_window_state())
+            })?;
+            state_guard.trigger = Some(trigger.clone());
+            trigger
+        }
+    };
+
+    // Only connect if no input context serves this buffer yet, in shared mode the
+    // context (and its receivers) is reused across buffers
+    let key = state_guard.context_key(buf);
+    if !connect_context(&mut state_guard, key, &trigger)? {
+        oxi::print!("{PLUGIN_NAME}: failed to connect to DBus");
+        return Ok(());
+    }
+    state_guard.loaded_buffers.insert(buf.handle());
+    state_guard.unloaded_buffers.remove(&buf.handle());
+    ignore_dbus_no_interface_error!(state_guard.deactivate_im(buf));
 
     // if already in insert mode, set the im
     let got_mode = api::get_mode();
     match &std::str::from_utf8(got_mode.mode.as_bytes()) {
         Ok("i") => {
-            ignore_dbus_no_interface_error!(state_guard.activate_im(buf));
+            state_guard.set_active_context(buf);
+            ignore_dbus_no_interface_error!(state_guard.set_focus(Some(key)));
+            ignore_dbus_no_interface_error!(apply_insert_im(&mut state_guard, buf));
         }
         _ => {}
     }
@@ -238,15 +525,120 @@ pub fn unload_plugin(state: Arc<Mutex<Fcitx5Plugin>>, buf: &Buffer) -> oxi::Resu
     // Reset and clear the input context if it exists
     ignore_dbus_no_interface_error!(state_guard.reset_im_ctx(buf));
 
-    state_guard.controller.remove(&buf.handle());
-    if let Some(ctx) = state_guard.ctx.remove(&buf.handle()) {
-        let _ = ctx.destroy_ic();
-    }
+    state_guard.loaded_buffers.remove(&buf.handle());
+    state_guard.unloaded_buffers.insert(buf.handle());
+
+    // A shared input context is only torn down once its last buffer is unloaded, those of
+    // the windows showing this buffer as soon as it is
+    state_guard.drop_unused_contexts();
 
     drop(state_guard);
 
     // Delete the augroup if it exists
     deregister_autocommands(state.clone(), buf)?;
+    deregister_keymaps(state, buf)
+}
+
+/// Forget everything kept for `buf` once it is deleted or wiped out, along with its
+/// keymaps and autocommands, and destroy the input contexts nothing uses anymore
+pub fn forget_buffer(state: Arc<Mutex<Fcitx5Plugin>>, buf: &Buffer) -> oxi::Result<()> {
+    let mut state_guard = lock_logged!(state, "PLUGIN_STATE");
+    let handle = buf.handle();
+    state_guard.loaded_buffers.remove(&handle);
+    state_guard.unloaded_buffers.remove(&handle);
+    state_guard.in_context.remove(&handle);
+    state_guard.keymaps_registered.remove(&handle);
+    state_guard.existing_keymaps_insert.remove(&handle);
+    state_guard.drop_unused_contexts();
+
+    drop(state_guard);
+
+    deregister_autocommands(state, buf)
+}
+
+/// Destroy every input context, fcitx5 would otherwise keep them until the DBus
+/// connections time out after Neovim exits
+pub fn destroy_all_contexts(state: Arc<Mutex<Fcitx5Plugin>>) {
+    let mut state_guard = lock_logged!(state, "PLUGIN_STATE");
+    ignore_dbus_no_interface_error!(state_guard.set_focus(None));
+    let keys: Vec<_> = state_guard.ctx.keys().copied().collect();
+    for key in keys {
+        state_guard.drop_context(&key);
+    }
+}
+
+/// Start watching for Fcitx5 restarts, so that input contexts are transparently recreated
+pub fn watch_for_reconnect(state: Arc<Mutex<Fcitx5Plugin>>) -> oxi::Result<()> {
+    let mut state_guard = state.lock().unwrap();
+    if state_guard.reconnect_trigger.is_some() {
+        return Ok(());
+    }
+
+    let reconnect_trigger = AsyncHandle::new(move || reconnect_contexts(get_state()))?;
+    watch_fcitx5_restart(reconnect_trigger.clone()).map_err(as_api_error)?;
+    state_guard.reconnect_trigger = Some(reconnect_trigger);
+
+    Ok(())
+}
+
+/// Recreate the controller and input context of every loaded context, after Fcitx5 has
+/// restarted and the old ones are gone
+pub fn reconnect_contexts(state: Arc<Mutex<Fcitx5Plugin>>) -> oxi::Result<()> {
+    let mut state_guard = lock_logged!(state, "PLUGIN_STATE");
+
+    let trigger = match state_guard.trigger.clone() {
+        Some(trigger) => trigger,
+        // Never loaded, nothing to reconnect
+        None => return Ok(()),
+    };
+    let im_window_state = state_guard.im_window_state.clone();
+
+    let keys: Vec<_> = state_guard.ctx.keys().copied().collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    // Close the connections of the old input contexts, which stops their receivers, even
+    // if some fail to reconnect.  Buffers left without an input context can be loaded
+    // again.
+    let mut failed = false;
+    for key in keys {
+        state_guard.drop_context(&key);
+        if !connect_context(&mut state_guard, key, &trigger)? {
+            failed = true;
+            continue;
+        }
+        // The new input context may have started out with another input method
+        let _ = state_guard.refresh_context_im_cache(key);
+    }
+    if failed {
+        oxi::print!("{PLUGIN_NAME}: failed to reconnect to Fcitx5");
+    }
+
+    // Whatever was shown belonged to the old input contexts
+    lock_logged!(im_window_state, "IMWindowState").clear();
+    trigger.send()?;
+
+    // Restore the IM state of the current buffer
+    let buf = api::get_current_buf();
+    if state_guard.initialized(&buf) {
+        let got_mode = api::get_mode();
+        match &std::str::from_utf8(got_mode.mode.as_bytes()) {
+            Ok("i") => {
+                state_guard.set_active_context(&buf);
+                let key = state_guard.context_key(&buf);
+                ignore_dbus_no_interface_error!(state_guard.set_focus(Some(key)));
+                ignore_dbus_no_interface_error!(apply_insert_im(
+                    &mut state_guard,
+                    &buf
+                ));
+            }
+            _ => {
+                ignore_dbus_no_interface_error!(state_guard.deactivate_im(&buf));
+            }
+        }
+    }
+
     Ok(())
 }
 
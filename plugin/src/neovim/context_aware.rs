@@ -0,0 +1,55 @@
+//! Context-aware switching: in code, the input method is only active inside comments and
+//! strings, see [`ContextAware`]
+
+use nvim_oxi::api::{self, Buffer};
+
+use crate::plugin::{config::ContextAware, Fcitx5Plugin};
+
+use super::rules::buffer_option;
+
+/// Names of the treesitter captures at the character before the cursor, or of the
+/// (translated) syntax groups there when treesitter does not highlight the buffer
+const SYNTAX_NAMES_AT_CURSOR: &str = r#"(function(buf)
+  local row, col = unpack(vim.api.nvim_win_get_cursor(0))
+  row, col = row - 1, math.max(col - 1, 0)
+  local names = {}
+  if vim.treesitter.highlighter.active[buf] then
+    for _, capture in ipairs(vim.treesitter.get_captures_at_pos(buf, row, col)) do
+      table.insert(names, capture.capture)
+    end
+  else
+    for _, id in ipairs(vim.fn.synstack(row + 1, col + 1)) do
+      table.insert(names, vim.fn.synIDattr(vim.fn.synIDtrans(id), "name"):lower())
+    end
+  end
+  return table.concat(names, ",")
+end)(_A)"#;
+
+impl ContextAware {
+    /// Whether `name` is one of the captures, or a more specific capture like
+    /// "comment.documentation"
+    fn matches(&self, name: &str) -> bool {
+        self.captures.iter().any(|capture| {
+            name == capture
+                || name
+                    .strip_prefix(capture.as_str())
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+    }
+}
+
+/// Whether the cursor is inside a comment or a string of `buf`, or `None` when
+/// context-aware switching does not apply to it.  Must be called on the main thread.
+pub fn cursor_in_context(state: &Fcitx5Plugin, buf: &Buffer) -> Option<bool> {
+    let config = &state.config.as_ref()?.context_aware;
+    if !config.filetypes.contains(&buffer_option(buf, "filetype")) {
+        return None;
+    }
+
+    let names = api::call_function::<_, String>(
+        "luaeval",
+        (SYNTAX_NAMES_AT_CURSOR, buf.handle()),
+    )
+    .unwrap_or_default();
+    Some(names.split(',').any(|name| config.matches(name)))
+}
@@ -0,0 +1,187 @@
+//! IM state change events, delivered to subscribed Lua callbacks and `User` autocommands
+
+use nvim_oxi::{
+    self as oxi,
+    api::{self, opts::EchoOpts, opts::ExecAutocmdsOpts},
+    Array, Dictionary, Function, Object,
+};
+
+use crate::{
+    fcitx5::candidates::Candidate,
+    lock_logged,
+    plugin::{get_state, PLUGIN_NAME},
+};
+
+/// Kinds of events Lua can subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    IMChanged,
+    Commit,
+    PreeditChanged,
+    Candidates,
+}
+
+impl EventKind {
+    /// Pattern of the `User` autocommand fired for this kind of event
+    pub fn autocmd_pattern(&self) -> &'static str {
+        match self {
+            EventKind::IMChanged => "Fcitx5IMChanged",
+            EventKind::Commit => "Fcitx5Commit",
+            EventKind::PreeditChanged => "Fcitx5PreeditChanged",
+            EventKind::Candidates => "Fcitx5Candidates",
+        }
+    }
+}
+
+/// An event received from fcitx5, queued by the receiver threads and emitted on the main
+/// thread
+#[derive(Clone, Debug)]
+pub enum PluginEvent {
+    IMChanged {
+        name: String,
+        unique_name: String,
+        language: String,
+    },
+    Commit(String),
+    PreeditChanged {
+        text: String,
+        cursor: Option<usize>,
+    },
+    Candidates {
+        candidates: Vec<Candidate>,
+        selected_index: usize,
+        has_prev: bool,
+        has_next: bool,
+        /// Whether comments are guessed, as set by `window.split_comments`
+        split_comments: bool,
+    },
+}
+
+impl PluginEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            PluginEvent::IMChanged { .. } => EventKind::IMChanged,
+            PluginEvent::Commit(_) => EventKind::Commit,
+            PluginEvent::PreeditChanged { .. } => EventKind::PreeditChanged,
+            PluginEvent::Candidates { .. } => EventKind::Candidates,
+        }
+    }
+
+    /// The table passed to callbacks, and as `data` to autocommands
+    fn to_dictionary(&self) -> Dictionary {
+        match self {
+            PluginEvent::IMChanged {
+                name,
+                unique_name,
+                language,
+            } => Dictionary::from_iter([
+                ("name", name.as_str()),
+                ("unique_name", unique_name.as_str()),
+                ("language", language.as_str()),
+            ]),
+            PluginEvent::Commit(text) => {
+                Dictionary::from_iter([("text", text.as_str())])
+            }
+            PluginEvent::PreeditChanged { text, cursor } => Dictionary::from_iter([
+                ("text", Object::from(text.as_str())),
+                (
+                    "cursor",
+                    cursor.map_or(Object::nil(), |pos| Object::from(pos as i64)),
+                ),
+            ]),
+            PluginEvent::Candidates {
+                candidates,
+                selected_index,
+                has_prev,
+                has_next,
+                split_comments,
+            } => Dictionary::from_iter([
+                (
+                    "candidates",
+                    Object::from(Array::from_iter(candidates.iter().map(
+                        |candidate| {
+                            Dictionary::from_iter([
+                                ("label", candidate.display.as_str()),
+                                ("text", candidate.text.as_str()),
+                                (
+                                    "comment",
+                                    if *split_comments {
+                                        candidate.guess_comment().1
+                                    } else {
+                                        ""
+                                    },
+                                ),
+                            ])
+                        },
+                    ))),
+                ),
+                ("selected", Object::from(*selected_index as i64)),
+                ("has_prev", Object::from(*has_prev)),
+                ("has_next", Object::from(*has_next)),
+            ]),
+        }
+    }
+}
+
+/// Register a Lua callback for events of `kind`
+pub fn subscribe(kind: EventKind, callback: Function<Dictionary, ()>) {
+    let state = get_state();
+    let mut state_guard = lock_logged!(state, "PLUGIN_STATE");
+    state_guard
+        .subscribers
+        .entry(kind)
+        .or_default()
+        .push(callback);
+}
+
+/// Call the subscribed callbacks and fire the `User` autocommand of every event.  Must be
+/// called on the main thread, without holding any lock as callbacks may call back into the
+/// plugin.
+pub fn emit_events(events: Vec<PluginEvent>) {
+    for event in events {
+        let kind = event.kind();
+        let data = event.to_dictionary();
+
+        let callbacks = {
+            let state = get_state();
+            let state_guard = lock_logged!(state, "PLUGIN_STATE");
+            state_guard
+                .subscribers
+                .get(&kind)
+                .cloned()
+                .unwrap_or_default()
+        };
+        for callback in callbacks {
+            if let Err(e) = callback.call(data.clone()) {
+                let _ = api::echo(
+                    vec![(
+                        format!(
+                            "{PLUGIN_NAME}: {} callback failed: {e}",
+                            kind.autocmd_pattern()
+                        )
+                        .as_str(),
+                        Some("WarningMsg"),
+                    )],
+                    true,
+                    &EchoOpts::default(),
+                );
+            }
+        }
+
+        let _ = api::exec_autocmds(
+            ["User"],
+            &ExecAutocmdsOpts::builder()
+                .patterns(kind.autocmd_pattern())
+                .data(data)
+                .build(),
+        );
+    }
+}
+
+/// Emit `events` once the main loop is free
+pub fn schedule_events(events: Vec<PluginEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    oxi::schedule(move |_| emit_events(events));
+}
@@ -3,14 +3,25 @@ use nvim_oxi::{
     api::{
         self,
         opts::{EchoOpts, SetKeymapOpts},
+        Buffer,
     },
+    Array, Dictionary, Function, Object,
 };
 
+use crate::fcitx5::input_methods::InputMethodInfo;
 use crate::plugin::{
-    config::PluginConfig, get_im_window_state, get_state, PLUGIN_NAME,
+    config::PluginConfig, get_im_window_state, get_state, Fcitx5Plugin, PLUGIN_NAME,
 };
 
-use super::commands::toggle_plugin;
+use super::{
+    autocmds::{
+        register_auto_load, register_focus_tracking, register_lifecycle_autocmds,
+    },
+    commands::{process_im_window_updates, toggle_plugin, watch_for_reconnect},
+    events::{subscribe, EventKind},
+    highlights::define_highlights,
+    keymaps::register_plug_keymaps,
+};
 
 pub fn setup(config: PluginConfig) -> bool {
     // set config into plugin state
@@ -24,6 +35,7 @@ pub fn setup(config: PluginConfig) -> bool {
     // This is a "safe" context for api::create_buf.
     let im_window_state = get_im_window_state();
     let mut im_state_guard = im_window_state.lock().unwrap();
+    im_state_guard.config = config.clone();
     if im_state_guard.buffer.is_none() {
         match api::create_buf(false, true) {
             Ok(buf) => {
@@ -47,12 +59,50 @@ pub fn setup(config: PluginConfig) -> bool {
     }
     drop(im_state_guard);
 
+    if let Err(e) = define_highlights() {
+        oxi::print!("{PLUGIN_NAME}: Could not define highlight groups: {e}");
+        return false;
+    }
+
     // Initialize the plugin's commands
     if let Err(e) = crate::neovim::commands::register_commands() {
         oxi::print!("{PLUGIN_NAME}: Could not setup commands: {e}");
         return false;
     }
 
+    // Not fatal, the plugin still works until Fcitx5 restarts
+    if let Err(e) = watch_for_reconnect(get_state()) {
+        let _ = api::echo(
+            vec![(
+                format!("{PLUGIN_NAME}: Could not watch for Fcitx5 restarts: {e}")
+                    .as_str(),
+                Some("WarningMsg"),
+            )],
+            true,
+            &EchoOpts::default(),
+        );
+    }
+
+    if let Err(e) = register_plug_keymaps() {
+        oxi::print!("{PLUGIN_NAME}: Could not setup <Plug> keymaps: {e}");
+        return false;
+    }
+
+    if let Err(e) = register_auto_load(&config.auto_load) {
+        oxi::print!("{PLUGIN_NAME}: Could not setup auto-loading: {e}");
+        return false;
+    }
+
+    if let Err(e) = register_focus_tracking() {
+        oxi::print!("{PLUGIN_NAME}: Could not setup focus tracking: {e}");
+        return false;
+    }
+
+    if let Err(e) = register_lifecycle_autocmds() {
+        oxi::print!("{PLUGIN_NAME}: Could not setup buffer cleanup: {e}");
+        return false;
+    }
+
     if let Some(on_key) = config.on_key {
         if let Err(e) = api::set_keymap(
             api::types::Mode::Normal,
@@ -91,12 +141,179 @@ pub fn setup(config: PluginConfig) -> bool {
 }
 
 // must accept 1 parameter, use `()` to let the exported lua function take no parameter
-pub fn get_im(_: ()) -> oxi::String {
+/// Returns the cached current input method, and how many milliseconds ago it was last
+/// updated (`nil` if unknown)
+pub fn get_im(_: ()) -> (oxi::String, Object) {
     let state = get_state();
     let state_guard = state.lock().unwrap();
     if let Ok(im) = state_guard.get_im(&api::get_current_buf()) {
-        im.into()
+        (
+            im.name.as_str().into(),
+            Object::from(im.age().as_millis() as i64),
+        )
     } else {
-        "".into()
+        ("".into(), Object::nil())
+    }
+}
+
+/// Call `callback` with `{ name, unique_name, language }` whenever the input method changes
+pub fn on_im_change(callback: Function<Dictionary, ()>) {
+    subscribe(EventKind::IMChanged, callback);
+}
+
+/// Call `callback` with `{ text }` whenever fcitx5 commits text
+pub fn on_commit(callback: Function<Dictionary, ()>) {
+    subscribe(EventKind::Commit, callback);
+}
+
+/// Call `callback` with `{ text, cursor }` whenever the preedit changes
+pub fn on_preedit_change(callback: Function<Dictionary, ()>) {
+    subscribe(EventKind::PreeditChanged, callback);
+}
+
+/// Call `callback` with `{ candidates = { { label, text, comment }, ... }, selected,
+/// has_prev, has_next }` whenever the candidate list changes
+pub fn on_candidates(callback: Function<Dictionary, ()>) {
+    subscribe(EventKind::Candidates, callback);
+}
+
+/// Run `action` on the ongoing composition of the current buffer, then update the IM
+/// window.  Returns whether there was a composition and `action` succeeded.
+fn on_composition(
+    action: impl FnOnce(&Fcitx5Plugin, &Buffer) -> fcitx5_dbus::zbus::Result<()>,
+) -> bool {
+    let state = get_state();
+    let state_guard = state.lock().unwrap();
+    let buf = api::get_current_buf();
+    let im_window_state = get_im_window_state();
+    if !state_guard.initialized(&buf) || !im_window_state.lock().unwrap().is_composing()
+    {
+        return false;
+    }
+    if let Err(e) = action(&state_guard, &buf) {
+        warn(&format!("{PLUGIN_NAME}: {e}"));
+        return false;
+    }
+    im_window_state.lock().unwrap().mark_for_update();
+    drop(state_guard);
+    if let Err(e) = process_im_window_updates(im_window_state) {
+        warn(&format!(
+            "{PLUGIN_NAME}: Could not update the IM window: {e}"
+        ));
+    }
+    true
+}
+
+/// Select the `n`th (1-based) candidate of the current page, returns whether it succeeded
+pub fn select_candidate(n: i64) -> bool {
+    let count = get_im_window_state().lock().unwrap().candidates.len();
+    match usize::try_from(n) {
+        Ok(n) if (1..=count).contains(&n) => {
+            on_composition(|state, buf| state.select_candidate(buf, n - 1))
+        }
+        _ => false,
+    }
+}
+
+/// Show the next page of candidates, returns whether it succeeded
+pub fn next_page(_: ()) -> bool {
+    on_composition(|state, buf| state.next_page(buf))
+}
+
+/// Show the previous page of candidates, returns whether it succeeded
+pub fn prev_page(_: ()) -> bool {
+    on_composition(|state, buf| state.prev_page(buf))
+}
+
+/// Move to the next candidate, returns whether it succeeded
+pub fn next_candidate(_: ()) -> bool {
+    on_composition(|state, buf| state.next_candidate(buf))
+}
+
+/// Move to the previous candidate, returns whether it succeeded
+pub fn prev_candidate(_: ()) -> bool {
+    on_composition(|state, buf| state.prev_candidate(buf))
+}
+
+fn warn(msg: &str) {
+    let _ = api::echo(vec![(msg, Some("WarningMsg"))], true, &EchoOpts::default());
+}
+
+fn input_method_to_dictionary(im: &InputMethodInfo) -> Dictionary {
+    Dictionary::from_iter([
+        ("unique_name", im.unique_name.as_str()),
+        ("name", im.name.as_str()),
+        ("native_name", im.native_name.as_str()),
+        ("icon", im.icon.as_str()),
+        ("label", im.label.as_str()),
+        ("language", im.language.as_str()),
+    ])
+}
+
+/// Input methods of the current group, as `{ unique_name, name, native_name, icon, label,
+/// language }` tables
+pub fn list_input_methods(_: ()) -> Array {
+    let state = get_state();
+    let state_guard = state.lock().unwrap();
+    match state_guard.list_input_methods(&api::get_current_buf()) {
+        Ok(ims) => Array::from_iter(ims.iter().map(input_method_to_dictionary)),
+        Err(e) => {
+            warn(&e.to_string());
+            Array::new()
+        }
+    }
+}
+
+/// Input method groups, as `{ groups = { ... }, current = "..." }`
+pub fn list_input_method_groups(_: ()) -> Dictionary {
+    let state = get_state();
+    let state_guard = state.lock().unwrap();
+    match state_guard.list_input_method_groups(&api::get_current_buf()) {
+        Ok((groups, current)) => Dictionary::from_iter([
+            ("groups", Object::from(Array::from_iter(groups))),
+            ("current", Object::from(current)),
+        ]),
+        Err(e) => {
+            warn(&e.to_string());
+            Dictionary::new()
+        }
+    }
+}
+
+/// Switch the current buffer to the input method with unique name `name`, returns whether
+/// it succeeded
+pub fn set_im(name: String) -> bool {
+    let state = get_state();
+    let mut state_guard = state.lock().unwrap();
+    let buf = api::get_current_buf();
+    if !state_guard.initialized(&buf) {
+        warn(&format!(
+            "{PLUGIN_NAME}: not loaded. Run :Fcitx5PluginLoad first"
+        ));
+        return false;
+    }
+    match state_guard.set_im_checked(&buf, &name) {
+        Ok(()) => true,
+        Err(e) => {
+            warn(&format!(
+                "{PLUGIN_NAME}: could not set input method '{name}': {e}"
+            ));
+            false
+        }
+    }
+}
+
+/// Switch to the input method group named `group`, returns whether it succeeded
+pub fn switch_im_group(group: String) -> bool {
+    let state = get_state();
+    let state_guard = state.lock().unwrap();
+    match state_guard.switch_im_group(&api::get_current_buf(), &group) {
+        Ok(()) => true,
+        Err(e) => {
+            warn(&format!(
+                "{PLUGIN_NAME}: could not switch to input method group '{group}': {e}"
+            ));
+            false
+        }
     }
 }
@@ -0,0 +1,71 @@
+//! Highlight groups used by the plugin
+
+use nvim_oxi::{
+    self as oxi,
+    api::{self, opts::SetHighlightOpts},
+};
+
+use crate::fcitx5::format::{FormattedText, TextFormatFlag};
+
+/// Preedit text
+pub static HL_PREEDIT: &str = "Fcitx5Preedit";
+/// Part of the preedit that fcitx5 asks to highlight, e.g. the segment being converted
+pub static HL_PREEDIT_HIGHLIGHT: &str = "Fcitx5PreeditHighlight";
+/// Candidates in the IM window
+pub static HL_CANDIDATE: &str = "Fcitx5Candidate";
+/// The selected candidate in the IM window
+pub static HL_CANDIDATE_SELECTED: &str = "Fcitx5CandidateSelected";
+/// Comments of candidates in the IM window, e.g. pronunciations or emoji names
+pub static HL_CANDIDATE_COMMENT: &str = "Fcitx5CandidateComment";
+/// Auxiliary text in the IM window, e.g. the current input method or engine hints
+pub static HL_AUX: &str = "Fcitx5Aux";
+
+/// Define the plugin's highlight groups, as `default` links so that colorschemes and users
+/// can override them
+pub fn define_highlights() -> oxi::Result<()> {
+    for (name, link) in [
+        (HL_PREEDIT, "Underlined"),
+        (HL_PREEDIT_HIGHLIGHT, "Visual"),
+        (HL_CANDIDATE, "NormalFloat"),
+        (HL_CANDIDATE_SELECTED, "PmenuSel"),
+        (HL_CANDIDATE_COMMENT, "Comment"),
+        (HL_AUX, "Comment"),
+    ] {
+        api::set_hl(
+            0,
+            name,
+            &SetHighlightOpts::builder().link(link).default(true).build(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Highlight groups for a piece of preedit text, later groups take precedence
+pub fn preedit_highlight_groups(segment: &FormattedText) -> Vec<&'static str> {
+    formatted_highlight_groups(HL_PREEDIT, segment)
+}
+
+/// Highlight groups for a piece of formatted text: `base`, followed by one group for each of
+/// its format flags.  Later groups take precedence.
+pub fn formatted_highlight_groups(
+    base: &'static str,
+    segment: &FormattedText,
+) -> Vec<&'static str> {
+    let mut groups = vec![base];
+    if segment.has_flag(TextFormatFlag::UNDERLINE) {
+        groups.push("Underlined");
+    }
+    if segment.has_flag(TextFormatFlag::BOLD) {
+        groups.push("@markup.strong");
+    }
+    if segment.has_flag(TextFormatFlag::ITALIC) {
+        groups.push("@markup.italic");
+    }
+    if segment.has_flag(TextFormatFlag::STRIKE) {
+        groups.push("@markup.strikethrough");
+    }
+    if segment.has_flag(TextFormatFlag::HIGHLIGHT) {
+        groups.push(HL_PREEDIT_HIGHLIGHT);
+    }
+    groups
+}
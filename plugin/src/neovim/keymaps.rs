@@ -2,24 +2,31 @@ use std::sync::{Arc, Mutex};
 
 use nvim_oxi::{
     self as oxi,
-    api::{self, opts::SetKeymapOpts, Buffer},
+    api::{
+        self,
+        opts::{EchoOpts, SetKeymapOpts},
+        Buffer,
+    },
 };
 
 use crate::{
-    plugin::{
-        get_im_window_state, get_state, Fcitx5Plugin, KEYMAPS, PASSTHROUGH_KEYMAPS,
-        PLUGIN_NAME,
-    },
+    fcitx5::keys::{send_key, translate_key},
+    plugin::{get_im_window_state, get_state, Fcitx5Plugin, KEYMAPS, PLUGIN_NAME},
     utils::{as_api_error, do_feedkeys_noremap},
 };
 
-use super::commands::process_im_window_updates;
+use super::{
+    commands::process_im_window_updates,
+    functions::{
+        next_candidate, next_page, prev_candidate, prev_page, select_candidate,
+    },
+};
 
 fn handle_special_key(nvim_keycode: &str, buf: &Buffer) -> oxi::Result<()> {
     let state = get_state();
     let state_guard = state.lock().unwrap();
     let im_window_guard = state_guard.im_window_state.lock().unwrap();
-    if !im_window_guard.is_visible() || im_window_guard.is_showing_current_im() {
+    if !im_window_guard.is_composing() || im_window_guard.is_showing_current_im() {
         // call the original keymap, if there is one
         if let Some(buf_keymaps) =
             state_guard.existing_keymaps_insert.get(&buf.handle())
@@ -68,27 +75,24 @@ fn handle_special_key(nvim_keycode: &str, buf: &Buffer) -> oxi::Result<()> {
     drop(im_window_guard);
     drop(state_guard);
 
-    match nvim_keycode.to_lowercase().as_str() {
-        key @ _ if PASSTHROUGH_KEYMAPS.keys().any(|k| k.to_lowercase() == key) => {
-            let state_guard = state.lock().unwrap();
-            let mut im_window_guard = state_guard.im_window_state.lock().unwrap();
-            let ctx = state_guard.ctx.get(&buf.handle()).unwrap();
-            let (key_state, key_code) = PASSTHROUGH_KEYMAPS.get(key).unwrap_or_else(|| {
-                unreachable!("{PLUGIN_NAME}: A key '{key}' is supplied, but there has not been a mapping defined for it!")
-            });
-            ctx.process_key_event(*key_code, 0, *key_state, false, 0)
-                .map_err(as_api_error)?;
-            im_window_guard.mark_for_update();
-            drop(im_window_guard);
-            drop(state_guard);
-            process_im_window_updates(get_im_window_state())?;
-            Ok(())
-        }
-        key @ _ if KEYMAPS.keys().any(|k| k.to_lowercase() == key) => {
-            KEYMAPS.get(key).unwrap()(state, buf)
-        }
-        _ => Ok(()),
+    let key = nvim_keycode.to_lowercase();
+    if let Some(handler) = KEYMAPS.get(&key) {
+        return handler(state, buf);
     }
+
+    let Some(key) = translate_key(nvim_keycode) else {
+        return Ok(());
+    };
+    let state_guard = state.lock().unwrap();
+    let mut im_window_guard = state_guard.im_window_state.lock().unwrap();
+    im_window_guard.active_context = Some(state_guard.context_key(buf));
+    let ctx = state_guard.get_ctx(buf).unwrap();
+    send_key(ctx, key, state_guard.key_options().send_release).map_err(as_api_error)?;
+    im_window_guard.mark_for_update();
+    drop(im_window_guard);
+    drop(state_guard);
+    process_im_window_updates(get_im_window_state())?;
+    Ok(())
 }
 
 pub fn register_keymaps(
@@ -112,7 +116,30 @@ pub fn register_keymaps(
     state_guard.store_original_keymaps(&buf)?;
     state_guard.keymaps_registered.insert(buf.handle(), true);
 
-    for k in KEYMAPS.keys().chain(PASSTHROUGH_KEYMAPS.keys()) {
+    let intercepted_keys = state_guard.key_options().intercept;
+    drop(state_guard);
+
+    let keys = KEYMAPS
+        .keys()
+        .cloned()
+        .chain(intercepted_keys.into_iter().filter(|k| {
+            if translate_key(k).is_some() {
+                return true;
+            }
+            let _ = api::echo(
+                vec![(
+                    format!(
+                        "{PLUGIN_NAME}: Ignoring unknown key '{k}' in keys.intercept"
+                    )
+                    .as_str(),
+                    Some("WarningMsg"),
+                )],
+                true,
+                &EchoOpts::default(),
+            );
+            false
+        }));
+    for k in keys {
         buf.set_keymap(
             api::types::Mode::Insert,
             &k,
@@ -127,3 +154,84 @@ pub fn register_keymaps(
 
     Ok(())
 }
+
+/// Remove the keymaps [`register_keymaps`] set for `buf`, and put back those they replaced
+pub fn deregister_keymaps(
+    state: Arc<Mutex<Fcitx5Plugin>>,
+    buf: &Buffer,
+) -> oxi::Result<()> {
+    let mut state_guard = state.lock().unwrap();
+    if state_guard.keymaps_registered.remove(&buf.handle()) != Some(true) {
+        return Ok(());
+    }
+    let original_keymaps = state_guard
+        .existing_keymaps_insert
+        .remove(&buf.handle())
+        .unwrap_or_default();
+    let intercepted_keys = state_guard.key_options().intercept;
+    drop(state_guard);
+
+    let mut buf = buf.clone();
+    for k in KEYMAPS.keys().cloned().chain(intercepted_keys) {
+        // ignore keys that were not mapped, or unmapped since
+        let _ = buf.del_keymap(api::types::Mode::Insert, &k);
+    }
+    for km in original_keymaps.into_values() {
+        let mut opts = SetKeymapOpts::builder();
+        opts.noremap(km.noremap)
+            .silent(km.silent)
+            .expr(km.expr)
+            .nowait(km.nowait);
+        if let Some(callback) = km.callback {
+            opts.callback(callback);
+        }
+        buf.set_keymap(
+            api::types::Mode::Insert,
+            &km.lhs,
+            km.rhs.as_deref().unwrap_or(""),
+            &opts.build(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Insert mode `<Plug>` mappings of the candidate functions, e.g.
+/// `<Plug>(fcitx5-next-candidate)`, to bind keys to them without Lua
+pub fn register_plug_keymaps() -> oxi::Result<()> {
+    let actions: [(&str, fn(()) -> bool); 4] = [
+        ("next-page", next_page),
+        ("prev-page", prev_page),
+        ("next-candidate", next_candidate),
+        ("prev-candidate", prev_candidate),
+    ];
+    for (name, action) in actions {
+        api::set_keymap(
+            api::types::Mode::Insert,
+            &format!("<Plug>(fcitx5-{name})"),
+            "",
+            &SetKeymapOpts::builder()
+                .noremap(true)
+                .silent(true)
+                .callback(move |_| {
+                    action(());
+                })
+                .build(),
+        )?;
+    }
+    for n in 1..=10 {
+        api::set_keymap(
+            api::types::Mode::Insert,
+            &format!("<Plug>(fcitx5-select-candidate-{n})"),
+            "",
+            &SetKeymapOpts::builder()
+                .noremap(true)
+                .silent(true)
+                .callback(move |_| {
+                    select_candidate(n);
+                })
+                .build(),
+        )?;
+    }
+    Ok(())
+}
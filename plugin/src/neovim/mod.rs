@@ -2,5 +2,10 @@
 
 pub mod autocmds;
 pub mod commands;
+pub mod context_aware;
+pub mod events;
 pub mod functions;
+pub mod highlights;
 pub mod keymaps;
+pub mod preedit;
+pub mod rules;
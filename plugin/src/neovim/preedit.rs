@@ -0,0 +1,91 @@
+//! Inline preedit rendering with extmarks
+
+use std::sync::{Arc, Mutex};
+
+use nvim_oxi::{
+    self as oxi,
+    api::{self, opts::SetExtmarkOpts, types::ExtmarkVirtTextPosition, Buffer},
+};
+
+use crate::{fcitx5::format::FormattedText, lock_logged};
+
+use super::highlights::{preedit_highlight_groups, HL_PREEDIT};
+
+static PREEDIT_NAMESPACE: &str = "fcitx5-ui-rs-preedit";
+
+lazy_static::lazy_static! {
+    /// The buffer the inline preedit is currently drawn in, if any
+    static ref PREEDIT_BUFFER: Arc<Mutex<Option<Buffer>>> = Arc::new(Mutex::new(None));
+}
+
+/// Split the preedit into highlighted chunks, with `cursor_glyph` inserted at byte offset
+/// `cursor`
+pub fn preedit_chunks(
+    segments: &[FormattedText],
+    cursor: Option<usize>,
+    cursor_glyph: &str,
+) -> Vec<(String, Vec<&'static str>)> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    for segment in segments {
+        let groups = preedit_highlight_groups(segment);
+        let end = offset + segment.text.len();
+        match cursor {
+            Some(pos)
+                if (offset..end).contains(&pos)
+                    && segment.text.is_char_boundary(pos - offset) =>
+            {
+                let (before, after) = segment.text.split_at(pos - offset);
+                if !before.is_empty() {
+                    chunks.push((before.to_owned(), groups.clone()));
+                }
+                chunks.push((cursor_glyph.to_owned(), vec![HL_PREEDIT]));
+                if !after.is_empty() {
+                    chunks.push((after.to_owned(), groups));
+                }
+            }
+            _ => chunks.push((segment.text.clone(), groups)),
+        }
+        offset = end;
+    }
+    // Cursor after the last character
+    if cursor == Some(offset) {
+        chunks.push((cursor_glyph.to_owned(), vec![HL_PREEDIT]));
+    }
+    chunks
+}
+
+/// Draw the preedit as inline virtual text at the cursor of the current window, replacing
+/// the previously drawn one.  An empty preedit only clears the previous one.
+pub fn render_inline_preedit(
+    segments: Vec<FormattedText>,
+    cursor: Option<usize>,
+    cursor_glyph: String,
+) {
+    oxi::schedule(move |_| {
+        let ns_id = api::create_namespace(PREEDIT_NAMESPACE);
+
+        let mut preedit_buffer_guard = lock_logged!(PREEDIT_BUFFER, "PreeditBuffer");
+        if let Some(mut buffer) = preedit_buffer_guard.take() {
+            if buffer.is_valid() {
+                let _ = buffer.clear_namespace(ns_id, 0..);
+            }
+        }
+
+        if segments.iter().all(|segment| segment.text.is_empty()) {
+            return;
+        }
+
+        let win = api::get_current_win();
+        let mut buffer = api::get_current_buf();
+        if let Ok((row, col)) = win.get_cursor() {
+            let opts = SetExtmarkOpts::builder()
+                .virt_text(preedit_chunks(&segments, cursor, &cursor_glyph))
+                .virt_text_pos(ExtmarkVirtTextPosition::Inline)
+                .build();
+            if buffer.set_extmark(ns_id, row - 1, col, &opts).is_ok() {
+                *preedit_buffer_guard = Some(buffer);
+            }
+        }
+    });
+}
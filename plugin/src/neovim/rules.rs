@@ -0,0 +1,76 @@
+//! Per-buffer default input methods, see [`ImRule`]
+
+use fcitx5_dbus::zbus::Result;
+use nvim_oxi::api::{self, opts::OptionOpts, Buffer};
+
+use crate::plugin::{
+    config::{ImRule, RuleIM},
+    Fcitx5Plugin,
+};
+
+use super::context_aware::cursor_in_context;
+
+pub(crate) fn buffer_option(buf: &Buffer, name: &str) -> String {
+    api::get_option_value::<String>(
+        name,
+        &OptionOpts::builder().buffer(buf.clone()).build(),
+    )
+    .unwrap_or_default()
+}
+
+/// Whether the buffer named `name` matches the glob `pattern`, see `:h autocmd-pattern`
+fn bufname_matches(pattern: &str, name: &str) -> bool {
+    let subject = if pattern.contains('/') {
+        name
+    } else {
+        name.rsplit('/').next().unwrap_or(name)
+    };
+    api::call_function::<_, String>("glob2regpat", (pattern,))
+        .and_then(|regpat| api::call_function::<_, i64>("match", (subject, regpat)))
+        .is_ok_and(|idx| idx >= 0)
+}
+
+impl ImRule {
+    pub fn matches(&self, buf: &Buffer) -> bool {
+        (self.filetypes.is_empty()
+            || self.filetypes.contains(&buffer_option(buf, "filetype")))
+            && (self.buftypes.is_empty()
+                || self.buftypes.contains(&buffer_option(buf, "buftype")))
+            && (self.bufnames.is_empty() || {
+                let name = buf
+                    .get_name()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                self.bufnames
+                    .iter()
+                    .any(|pattern| bufname_matches(pattern, &name))
+            })
+    }
+}
+
+/// Enter insert mode in `buf`: inside comments and strings when context-aware switching
+/// applies to it (see [`cursor_in_context`]), then with the input method of the first rule
+/// matching it.  Must be called on the main thread.
+pub fn apply_insert_im(state: &mut Fcitx5Plugin, buf: &Buffer) -> Result<()> {
+    if let Some(in_context) = cursor_in_context(state, buf) {
+        state.in_context.insert(buf.handle(), in_context);
+        if !in_context {
+            return state.deactivate_im(buf);
+        }
+    }
+    apply_rule_im(state, buf)
+}
+
+/// Switch to the input method the first rule matching `buf` asks for, or activate the
+/// input method as usual when no rule matches.  Must be called on the main thread.
+pub fn apply_rule_im(state: &mut Fcitx5Plugin, buf: &Buffer) -> Result<()> {
+    let rule = state
+        .config
+        .as_ref()
+        .and_then(|config| config.rules.iter().find(|rule| rule.matches(buf)));
+    match rule.map(|rule| &rule.im) {
+        None | Some(RuleIM::Active(true)) => state.activate_im(buf),
+        Some(RuleIM::Active(false)) => state.deactivate_im(buf),
+        Some(RuleIM::Name(name)) => state.set_im(buf, name),
+    }
+}
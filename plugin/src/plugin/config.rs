@@ -5,10 +5,285 @@ use nvim_oxi::{
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Deserialize, Serialize)]
+/// How input contexts are allocated to buffers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextScope {
+    /// Every loaded buffer gets its own DBus connection and input context
+    #[default]
+    Buffer,
+    /// A single input context serves all loaded buffers
+    Shared,
+    /// Every window gets its own DBus connection and input context, so a buffer shown in
+    /// several windows composes separately in each of them
+    Window,
+}
+
+/// How candidates are arranged in the IM window
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CandidateLayout {
+    /// One candidate per line
+    #[default]
+    Vertical,
+    /// Candidates side by side, wrapped onto as many lines as needed
+    Horizontal,
+}
+
+/// Border of the IM window, see `:h nvim_open_win()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BorderStyle {
+    #[default]
+    None,
+    Single,
+    Double,
+    Rounded,
+    Solid,
+    Shadow,
+}
+
+/// Position of the IM window's title
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TitlePosition {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+/// Corner of the IM window placed at `row`/`col`, see `:h nvim_open_win()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Anchor {
+    #[default]
+    #[serde(rename = "NW")]
+    NorthWest,
+    #[serde(rename = "NE")]
+    NorthEast,
+    #[serde(rename = "SW")]
+    SouthWest,
+    #[serde(rename = "SE")]
+    SouthEast,
+}
+
+/// What the IM window follows as the cursor moves
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowFollow {
+    /// The cursor, which for an inline preedit is its cursor glyph
+    #[default]
+    Cursor,
+    /// The column where the preedit starts, so that the window stays put while typing
+    PreeditStart,
+}
+
+/// Size and appearance of the IM window, sizes are in display cells
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WindowOptions {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    /// Columns added to the width of the widest line
+    pub padding: u32,
+    /// The window keeps its current width as long as the content fits and the new width
+    /// differs by less than this many columns, to avoid jittering while typing
+    pub hysteresis: u32,
+    pub border: BorderStyle,
+    /// Window title, an empty title hides it
+    pub title: String,
+    pub title_pos: TitlePosition,
+    pub zindex: u32,
+    pub anchor: Anchor,
+    /// Row offset from the cursor
+    pub row: i32,
+    /// Column offset from the cursor
+    pub col: i32,
+    /// Open the window on the other side of the cursor when it does not fit on the screen
+    pub flip: bool,
+    /// Move the window left (or right) when it would leave the screen
+    pub shift: bool,
+    pub follow: WindowFollow,
+    pub winblend: u32,
+    /// Marks the selected candidate
+    pub selected_marker: String,
+    /// Marks that there is a previous page
+    pub prev_marker: String,
+    /// Marks that there is a next page
+    pub next_marker: String,
+    /// Repeated to draw the lines separating sections of the window
+    pub separator: String,
+    /// Marks the cursor position inside the preedit
+    pub cursor: String,
+    /// Show what follows the first space of a candidate as its comment.  fcitx5 does not
+    /// tell where comments start, so this is a guess.
+    pub split_comments: bool,
+    /// Align the comments of candidates in a column, in the vertical layout
+    pub align_comments: bool,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        Self {
+            min_width: 20,
+            max_width: 60,
+            min_height: 3,
+            max_height: 15,
+            padding: 2,
+            hysteresis: 4,
+            border: BorderStyle::None,
+            title: " Fcitx5 ".to_owned(),
+            title_pos: TitlePosition::Center,
+            zindex: 0x7fff,
+            anchor: Anchor::NorthWest,
+            row: 1,
+            col: 0,
+            flip: true,
+            shift: true,
+            follow: WindowFollow::Cursor,
+            winblend: 0,
+            selected_marker: "\u{25ba}".to_owned(),
+            prev_marker: "\u{25c4}".to_owned(),
+            next_marker: "\u{25ba}".to_owned(),
+            separator: "\u{2500}".to_owned(),
+            cursor: "\u{2502}".to_owned(),
+            split_comments: true,
+            align_comments: true,
+        }
+    }
+}
+
+/// Keys forwarded to fcitx5 while composing
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyOptions {
+    /// Insert mode keys, in `:h key-notation`, sent to fcitx5 instead of being handled by
+    /// Neovim while a preedit is shown
+    pub intercept: Vec<String>,
+    /// Also send key releases, with X keycodes and timestamps, for engines relying on them
+    pub send_release: bool,
+}
+
+impl Default for KeyOptions {
+    fn default() -> Self {
+        Self {
+            intercept: [
+                "<BS>",
+                "<C-H>",
+                "<C-W>",
+                "<Del>",
+                "<Left>",
+                "<Right>",
+                "<Up>",
+                "<Down>",
+                "<C-Left>",
+                "<C-Right>",
+                "<Home>",
+                "<End>",
+                "<PageUp>",
+                "<PageDown>",
+                "<Tab>",
+                "<S-Tab>",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            send_release: false,
+        }
+    }
+}
+
+/// Which buffers the plugin is loaded in automatically when they are entered
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AutoLoad {
+    pub enabled: bool,
+    /// Only load in buffers of these filetypes, every filetype when empty
+    pub filetypes: Vec<String>,
+    pub exclude_filetypes: Vec<String>,
+    pub exclude_buftypes: Vec<String>,
+}
+
+impl Default for AutoLoad {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filetypes: Vec::new(),
+            exclude_filetypes: Vec::new(),
+            exclude_buftypes: ["help", "nofile", "prompt", "quickfix", "terminal"]
+                .map(str::to_owned)
+                .to_vec(),
+        }
+    }
+}
+
+/// Filetypes in which the input method is only active inside comments and strings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContextAware {
+    pub filetypes: Vec<String>,
+    /// Treesitter captures the input method is active in, for buffers without treesitter
+    /// highlighting these are compared with the lowercased highlight groups instead
+    pub captures: Vec<String>,
+}
+
+impl Default for ContextAware {
+    fn default() -> Self {
+        Self {
+            filetypes: Vec::new(),
+            captures: ["comment", "string"].map(str::to_owned).to_vec(),
+        }
+    }
+}
+
+/// Input method of the buffers matched by an [`ImRule`]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RuleIM {
+    /// `true` activates the input method as usual, `false` keeps it inactive
+    Active(bool),
+    /// Unique name of the input method to switch to, e.g. "pinyin"
+    Name(String),
+}
+
+/// Input method a buffer enters insert mode with.  A rule applies to the buffers matching
+/// all of its non-empty lists.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ImRule {
+    #[serde(default)]
+    pub filetypes: Vec<String>,
+    #[serde(default)]
+    pub buftypes: Vec<String>,
+    /// Glob patterns of buffer names, matched against the full path when they contain a
+    /// slash and against the file name otherwise, like autocommand patterns
+    #[serde(default)]
+    pub bufnames: Vec<String>,
+    pub im: RuleIM,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct PluginConfig {
     #[serde(default)]
     pub on_key: Option<String>,
+    #[serde(default)]
+    pub context_scope: ContextScope,
+    /// Draw the preedit as virtual text at the cursor instead of inside the IM window
+    #[serde(default)]
+    pub inline_preedit: bool,
+    #[serde(default)]
+    pub candidate_layout: CandidateLayout,
+    #[serde(default)]
+    pub window: WindowOptions,
+    /// Default input methods, the first matching rule wins
+    #[serde(default)]
+    pub rules: Vec<ImRule>,
+    #[serde(default)]
+    pub auto_load: AutoLoad,
+    #[serde(default)]
+    pub context_aware: ContextAware,
+    #[serde(default)]
+    pub keys: KeyOptions,
 }
 
 impl FromObject for PluginConfig {
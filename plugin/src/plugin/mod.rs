@@ -1,29 +1,35 @@
 //! Plugin state management
 pub mod config;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use fcitx5_dbus::controller::ControllerProxyBlocking;
 use fcitx5_dbus::input_context::InputContextProxyBlocking;
-use fcitx5_dbus::utils::key_event::{
-    KeyState as Fcitx5KeyState, KeyVal as Fcitx5KeyVal,
-};
-use fcitx5_dbus::zbus::Result;
+use fcitx5_dbus::zbus::{Error, Result};
 use nvim_oxi::{
     self as oxi,
-    api::{self, types::KeymapInfos, Buffer},
+    api::{self, types::KeymapInfos, Buffer, Window},
+    conversion::FromObject,
+    libuv::AsyncHandle,
+    Array, Dictionary, Function,
 };
 
 use crate::{
-    fcitx5::candidates::IMWindowState,
+    fcitx5::{
+        candidates::{ClickAction, IMWindowState},
+        connection::disconnect,
+        input_methods::{group_input_methods, input_method_groups, InputMethodInfo},
+        keys::{send_key, translate_key},
+    },
     lock_logged,
-    neovim::commands::process_im_window_updates,
-    utils::{do_feedkeys_noremap, CURSOR_INDICATOR},
+    neovim::{commands::process_im_window_updates, events::EventKind},
+    utils::do_feedkeys_noremap,
 };
 use crate::{ignore_dbus_no_interface_error, utils::as_api_error};
 
-use config::PluginConfig;
+use config::{ContextScope, KeyOptions, PluginConfig};
 
 type BufferOriginalKeymaps = HashMap<String, KeymapInfos>;
 
@@ -44,9 +50,10 @@ lazy_static::lazy_static! {
                  }
                  let insert_text = im_window_guard
                      .preedit_text
-                     .replace([' ', CURSOR_INDICATOR], "")
+                     .replace(' ', "")
                      .clone();
-                 im_window_guard.mark_for_insert(insert_text);
+                 im_window_guard
+                     .mark_for_insert(insert_text, state_guard.context_key(buf));
                  ignore_dbus_no_interface_error!(state_guard.reset_im_ctx(buf));
                  drop(im_window_guard);
                  oxi::schedule(move |_| process_im_window_updates(im_window_state.clone()));
@@ -72,34 +79,119 @@ lazy_static::lazy_static! {
             })
         );
 
+        map.insert(
+            "<leftmouse>".to_owned(),
+            Box::new(move |state: Arc<Mutex<Fcitx5Plugin>>, buf: &Buffer| {
+                let mouse = api::call_function::<_, Dictionary>("getmousepos", Array::new())?;
+                let position = |key: &str| {
+                    mouse
+                        .get(key)
+                        .and_then(|value| i64::from_object(value.clone()).ok())
+                        .unwrap_or(0)
+                };
+
+                let state_guard = lock_logged!(state, "PLUGIN_STATE");
+                let im_window_state = state_guard.im_window_state.clone();
+                let action = {
+                    let im_window_guard = lock_logged!(im_window_state, "IMWindowState");
+                    let im_window = get_im_window();
+                    let im_window = lock_logged!(im_window, "IMWindow").clone();
+                    im_window
+                        .filter(|window| i64::from(window.handle()) == position("winid"))
+                        .and(im_window_guard.rendered_plan.as_ref())
+                        .and_then(|plan| {
+                            // Both are 1-based, 0 when outside of the text
+                            let line = usize::try_from(position("line") - 1).ok()?;
+                            let col = usize::try_from(position("column") - 1).ok()?;
+                            plan.click_action(line, col)
+                        })
+                };
+
+                let Some(action) = action else {
+                    // Not a click on a candidate, handle it as usual
+                    do_feedkeys_noremap("<LeftMouse>")?;
+                    return Ok(());
+                };
+                match action {
+                    ClickAction::SelectCandidate(idx) => {
+                        ignore_dbus_no_interface_error!(state_guard.select_candidate(buf, idx));
+                    }
+                    ClickAction::PrevPage => {
+                        ignore_dbus_no_interface_error!(state_guard.prev_page(buf));
+                    }
+                    ClickAction::NextPage => {
+                        ignore_dbus_no_interface_error!(state_guard.next_page(buf));
+                    }
+                }
+                lock_logged!(im_window_state, "IMWindowState").mark_for_update();
+                drop(state_guard);
+                process_im_window_updates(im_window_state)
+            })
+        );
+
         map
     };
-    pub(crate) static ref PASSTHROUGH_KEYMAPS: HashMap<String, (Fcitx5KeyState, Fcitx5KeyVal)> = HashMap::from([
-        ("<bs>".to_owned(), (Fcitx5KeyState::NoState, Fcitx5KeyVal::DELETE)),
-        ("<c-w>".to_owned(), (Fcitx5KeyState::Ctrl, Fcitx5KeyVal::DELETE)),
-        ("".to_owned(), (Fcitx5KeyState::Ctrl, Fcitx5KeyVal::DELETE)),
-        ("<left>".to_owned(), (Fcitx5KeyState::NoState, Fcitx5KeyVal::LEFT)),
-        ("<right>".to_owned(), (Fcitx5KeyState::NoState, Fcitx5KeyVal::RIGHT)),
-        ("<c-left>".to_owned(), (Fcitx5KeyState::Ctrl, Fcitx5KeyVal::LEFT)),
-        ("<c-right>".to_owned(), (Fcitx5KeyState::Ctrl, Fcitx5KeyVal::RIGHT)),
-        ("<tab>".to_owned(), (Fcitx5KeyState::NoState, Fcitx5KeyVal::from_char('\u{FF09}'))),
-        ("<s-tab>".to_owned(), (Fcitx5KeyState::Shift, Fcitx5KeyVal::from_char('\u{FF09}'))),
-    ]);
+}
+
+/// Last known current input method of an input context, kept up to date from fcitx5's
+/// signals so that reading it does not need a DBus roundtrip
+#[derive(Clone, Debug)]
+pub struct CachedIM {
+    /// Unique name of the input method, e.g. "pinyin" or "keyboard-us"
+    pub name: String,
+    pub updated_at: Instant,
+}
+
+impl CachedIM {
+    /// Time since the cache was last updated
+    pub fn age(&self) -> Duration {
+        self.updated_at.elapsed()
+    }
+}
+
+/// Identifies the input context a buffer talks to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContextKey {
+    /// Input context owned by a single buffer
+    Buffer(i32),
+    /// Input context shared by all loaded buffers
+    Shared,
+    /// Input context owned by a single window, whichever loaded buffer it shows
+    Window(i32),
 }
 
 // Structure to hold the plugin state
 pub struct Fcitx5Plugin {
     pub config: Option<PluginConfig>,
-    pub controller: HashMap<i32, ControllerProxyBlocking<'static>>,
+    /// Per-context controller
+    pub controller: HashMap<ContextKey, ControllerProxyBlocking<'static>>,
     /// Whether a buffer has been registered with our keymaps, we will not register it multiple
     /// times.
     pub keymaps_registered: HashMap<i32, bool>,
-    /// Per-buffer input context
-    pub ctx: HashMap<i32, InputContextProxyBlocking<'static>>,
+    /// Per-context input context, see [`Fcitx5Plugin::context_key`] for how buffers map to
+    /// contexts
+    pub ctx: HashMap<ContextKey, InputContextProxyBlocking<'static>>,
+    /// Buffers the plugin is currently loaded in
+    pub loaded_buffers: HashSet<i32>,
+    /// Buffers the plugin was explicitly unloaded from, auto-loading leaves them alone
+    pub unloaded_buffers: HashSet<i32>,
+    /// Whether the cursor was inside a comment or string when last checked, for buffers
+    /// with context-aware switching
+    pub in_context: HashMap<i32, bool>,
     /// Per-buffer augroup_id
     pub augroup_id: HashMap<i32, u32>,
     pub im_window_state: Arc<Mutex<IMWindowState>>,
     pub existing_keymaps_insert: HashMap<i32, BufferOriginalKeymaps>,
+    /// Wakes up the main thread to process IM window updates, shared by all contexts
+    pub trigger: Option<AsyncHandle>,
+    /// Wakes up the main thread to recreate all input contexts after Fcitx5 restarted
+    pub reconnect_trigger: Option<AsyncHandle>,
+    /// Lua callbacks subscribed to IM state change events
+    pub subscribers: HashMap<EventKind, Vec<Function<Dictionary, ()>>>,
+    /// Whether Neovim has the focus of the terminal and is not suspended
+    pub ui_focused: bool,
+    /// Input context fcitx5 was last told has the focus, see [`Fcitx5Plugin::set_focus`]
+    pub focused: Option<ContextKey>,
 }
 
 impl Fcitx5Plugin {
@@ -109,78 +201,352 @@ impl Fcitx5Plugin {
             controller: HashMap::new(),
             keymaps_registered: HashMap::new(),
             ctx: HashMap::new(),
+            loaded_buffers: HashSet::new(),
+            unloaded_buffers: HashSet::new(),
+            in_context: HashMap::new(),
             augroup_id: HashMap::new(),
             im_window_state: Arc::new(Mutex::new(IMWindowState::new())),
             existing_keymaps_insert: HashMap::new(),
+            trigger: None,
+            reconnect_trigger: None,
+            subscribers: HashMap::new(),
+            ui_focused: true,
+            focused: None,
+        }
+    }
+
+    pub fn context_scope(&self) -> ContextScope {
+        self.config
+            .as_ref()
+            .map(|config| config.context_scope)
+            .unwrap_or_default()
+    }
+
+    /// The key of the input context that serves `buf` in the current window
+    pub fn context_key(&self, buf: &Buffer) -> ContextKey {
+        match self.context_scope() {
+            ContextScope::Buffer => ContextKey::Buffer(buf.handle()),
+            ContextScope::Shared => ContextKey::Shared,
+            ContextScope::Window => ContextKey::Window(api::get_current_win().handle()),
+        }
+    }
+
+    /// Whether any loaded buffer still talks to the input context keyed by `key`
+    pub fn context_in_use(&self, key: &ContextKey) -> bool {
+        match key {
+            ContextKey::Buffer(handle) => self.loaded_buffers.contains(handle),
+            ContextKey::Shared => !self.loaded_buffers.is_empty(),
+            ContextKey::Window(handle) => {
+                let win = Window::from(*handle);
+                win.is_valid()
+                    && win
+                        .get_buf()
+                        .is_ok_and(|buf| self.loaded_buffers.contains(&buf.handle()))
+            }
+        }
+    }
+
+    /// Destroy the input contexts no loaded buffer talks to anymore
+    pub fn drop_unused_contexts(&mut self) {
+        let unused: Vec<_> = self
+            .ctx
+            .keys()
+            .filter(|key| !self.context_in_use(key))
+            .copied()
+            .collect();
+        for key in unused {
+            self.drop_context(&key);
+        }
+    }
+
+    /// Destroy the input context keyed by `key`, if it is connected
+    pub fn drop_context(&mut self, key: &ContextKey) {
+        if self.focused == Some(*key) {
+            self.focused = None;
+        }
+        self.controller.remove(key);
+        forget_current_im(key);
+        if let Some(ctx) = self.ctx.remove(key) {
+            disconnect(ctx);
         }
     }
 
+    pub fn get_controller(
+        &self,
+        buf: &Buffer,
+    ) -> Option<&ControllerProxyBlocking<'static>> {
+        self.controller.get(&self.context_key(buf))
+    }
+
+    pub fn get_ctx(&self, buf: &Buffer) -> Option<&InputContextProxyBlocking<'static>> {
+        self.ctx.get(&self.context_key(buf))
+    }
+
     pub fn initialized(&self, buf: &Buffer) -> bool {
-        self.controller.contains_key(&buf.handle())
-            && self.ctx.contains_key(&buf.handle())
+        self.loaded_buffers.contains(&buf.handle())
+            && self.get_controller(buf).is_some()
+            && self.get_ctx(buf).is_some()
     }
 
     pub fn reset_im_ctx(&self, buf: &Buffer) -> Result<()> {
-        if let Some(ctx) = self.ctx.get(&buf.handle()) {
+        if let Some(ctx) = self.get_ctx(buf) {
             ctx.reset()?;
         }
         Ok(())
     }
 
-    pub fn get_im(&self, buf: &Buffer) -> oxi::Result<String> {
-        if self.initialized(buf) {
-            self.controller
-                .get(&buf.handle())
-                .unwrap()
-                .current_input_method()
-                .map_err(|e| as_api_error(e).into())
-        } else {
-            Err(oxi::api::Error::Other(format!(
+    /// Move fcitx5's focus to the input context keyed by `key`, out of the one that had
+    /// it.  Our input contexts only hold the focus while typing in insert mode, with
+    /// Neovim focused; `None` leaves all of them unfocused.
+    pub fn set_focus(&mut self, key: Option<ContextKey>) -> Result<()> {
+        let key = key.filter(|_| self.ui_focused);
+        self.move_focus(key)
+    }
+
+    /// Move fcitx5's focus to the input context keyed by `key`, whether Neovim is focused
+    /// or not
+    fn move_focus(&mut self, key: Option<ContextKey>) -> Result<()> {
+        if self.focused == key {
+            return Ok(());
+        }
+        if let Some(ctx) = self.focused.and_then(|old| self.ctx.get(&old)) {
+            ctx.focus_out()?;
+        }
+        self.focused = None;
+        if let Some(ctx) = key.and_then(|key| self.ctx.get(&key)) {
+            ctx.focus_in()?;
+        }
+        self.focused = key;
+        Ok(())
+    }
+
+    /// Run `f` on `buf`'s controller with its input context focused, as the controller
+    /// acts on the focused input context.  The focus is given back afterwards.
+    fn with_focus(
+        &mut self,
+        buf: &Buffer,
+        f: impl FnOnce(&ControllerProxyBlocking<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let Some(controller) = self.get_controller(buf).cloned() else {
+            return Ok(());
+        };
+        if self.get_ctx(buf).is_none() {
+            return Ok(());
+        }
+        let previous = self.focused;
+        let key = self.context_key(buf);
+        self.move_focus(Some(key))?;
+        let result = f(&controller);
+        self.move_focus(previous)?;
+        result?;
+        self.refresh_im_cache(buf)
+    }
+
+    /// Query the current input method of `buf`'s input context over DBus and cache it
+    pub fn refresh_im_cache(&self, buf: &Buffer) -> Result<()> {
+        self.refresh_context_im_cache(self.context_key(buf))
+    }
+
+    /// Query the current input method of the input context keyed by `key` over DBus and
+    /// cache it
+    pub fn refresh_context_im_cache(&self, key: ContextKey) -> Result<()> {
+        if let Some(controller) = self.controller.get(&key) {
+            cache_current_im(key, controller.current_input_method()?);
+        }
+        Ok(())
+    }
+
+    /// The cached current input method, only queried over DBus if nothing is cached yet
+    pub fn get_im(&self, buf: &Buffer) -> oxi::Result<CachedIM> {
+        if !self.initialized(buf) {
+            return Err(oxi::api::Error::Other(format!(
                 "{PLUGIN_NAME}: could not get current input method (not initialized)",
             ))
-            .into())
+            .into());
+        }
+
+        let key = self.context_key(buf);
+        let cached = lock_logged!(CURRENT_IM, "CurrentIM").get(&key).cloned();
+        match cached {
+            Some(cached) => Ok(cached),
+            None => {
+                self.refresh_im_cache(buf).map_err(as_api_error)?;
+                lock_logged!(CURRENT_IM, "CurrentIM")
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| {
+                        oxi::api::Error::Other(format!(
+                            "{PLUGIN_NAME}: could not get current input method",
+                        ))
+                        .into()
+                    })
+            }
         }
     }
 
-    pub fn toggle_im(&self, buf: &Buffer) -> Result<()> {
-        if let (Some(controller), Some(ctx)) = (
-            self.controller.get(&buf.handle()),
-            self.ctx.get(&buf.handle()),
-        ) {
-            ctx.focus_in()?;
-            controller.toggle()?;
+    pub fn toggle_im(&mut self, buf: &Buffer) -> Result<()> {
+        self.with_focus(buf, |controller| controller.toggle())
+    }
+
+    pub fn activate_im(&mut self, buf: &Buffer) -> Result<()> {
+        self.with_focus(buf, |controller| controller.activate())
+    }
+
+    pub fn deactivate_im(&mut self, buf: &Buffer) -> Result<()> {
+        self.with_focus(buf, |controller| controller.deactivate())
+    }
+
+    /// Make `buf`'s input context the one whose composition the IM window shows
+    pub fn set_active_context(&self, buf: &Buffer) {
+        let key = self.context_key(buf);
+        lock_logged!(self.im_window_state, "IMWindowState").active_context = Some(key);
+    }
+
+    /// Forget the composition of `buf`'s input context when leaving it, returns whether the
+    /// IM window was showing it and needs an update
+    pub fn leave_context(&self, buf: &Buffer) -> bool {
+        let key = self.context_key(buf);
+        let mut im_window_guard = lock_logged!(self.im_window_state, "IMWindowState");
+        if im_window_guard.active_context != Some(key) {
+            return false;
+        }
+        im_window_guard.active_context = None;
+        im_window_guard.clear();
+        true
+    }
+
+    /// Select the candidate at `index` of the current page
+    pub fn select_candidate(&self, buf: &Buffer, index: usize) -> Result<()> {
+        if let Some(ctx) = self.get_ctx(buf) {
+            ctx.select_candidate(index as i32)?;
         }
         Ok(())
     }
 
-    pub fn activate_im(&self, buf: &Buffer) -> Result<()> {
-        if let (Some(controller), Some(ctx)) = (
-            self.controller.get(&buf.handle()),
-            self.ctx.get(&buf.handle()),
-        ) {
-            ctx.focus_in()?;
-            controller.activate()?;
+    pub fn prev_page(&self, buf: &Buffer) -> Result<()> {
+        if let Some(ctx) = self.get_ctx(buf) {
+            ctx.prev_page()?;
         }
         Ok(())
     }
 
-    pub fn deactivate_im(&self, buf: &Buffer) -> Result<()> {
-        if let (Some(controller), Some(ctx)) = (
-            self.controller.get(&buf.handle()),
-            self.ctx.get(&buf.handle()),
-        ) {
-            ctx.focus_in()?;
-            controller.deactivate()?;
+    pub fn next_page(&self, buf: &Buffer) -> Result<()> {
+        if let Some(ctx) = self.get_ctx(buf) {
+            ctx.next_page()?;
+        }
+        Ok(())
+    }
+
+    /// Move to the next candidate.  There is no DBus method for it, so this sends fcitx5's
+    /// default "Next Candidate" hotkey (Tab).
+    pub fn next_candidate(&self, buf: &Buffer) -> Result<()> {
+        self.send_hotkey(buf, "<Tab>")
+    }
+
+    /// Move to the previous candidate, by sending fcitx5's default "Previous Candidate"
+    /// hotkey (Shift+Tab)
+    pub fn prev_candidate(&self, buf: &Buffer) -> Result<()> {
+        self.send_hotkey(buf, "<S-Tab>")
+    }
+
+    fn send_hotkey(&self, buf: &Buffer, notation: &str) -> Result<()> {
+        if let (Some(ctx), Some(key)) = (self.get_ctx(buf), translate_key(notation)) {
+            send_key(ctx, key, self.key_options().send_release)?;
         }
         Ok(())
     }
 
+    /// Controller of `buf`'s input context, or of any other one if the plugin is not loaded
+    /// in `buf`.  For requests that do not depend on an input context.
+    pub fn any_controller(
+        &self,
+        buf: &Buffer,
+    ) -> Option<&ControllerProxyBlocking<'static>> {
+        self.get_controller(buf)
+            .or_else(|| self.controller.values().next())
+    }
+
+    fn not_loaded_error(&self) -> oxi::Error {
+        oxi::api::Error::Other(format!(
+            "{PLUGIN_NAME}: not loaded. Run :Fcitx5PluginLoad first"
+        ))
+        .into()
+    }
+
+    /// Input methods of the current input method group
+    pub fn list_input_methods(
+        &self,
+        buf: &Buffer,
+    ) -> oxi::Result<Vec<InputMethodInfo>> {
+        let controller = self
+            .any_controller(buf)
+            .ok_or_else(|| self.not_loaded_error())?;
+        let (_, current_group) =
+            input_method_groups(controller).map_err(as_api_error)?;
+        Ok(group_input_methods(controller, &current_group).map_err(as_api_error)?)
+    }
+
+    /// All input method groups, and the name of the current one
+    pub fn list_input_method_groups(
+        &self,
+        buf: &Buffer,
+    ) -> oxi::Result<(Vec<String>, String)> {
+        let controller = self
+            .any_controller(buf)
+            .ok_or_else(|| self.not_loaded_error())?;
+        Ok(input_method_groups(controller).map_err(as_api_error)?)
+    }
+
+    /// Switch `buf`'s input context to the input method named `name`
+    pub fn set_im(&mut self, buf: &Buffer, name: &str) -> Result<()> {
+        self.with_focus(buf, |controller| controller.set_current_im(name))
+    }
+
+    /// Like [`Self::set_im`], but fails on input methods of other groups, which fcitx5
+    /// silently ignores.  It asks fcitx5 for the group first, so rules don't use it.
+    pub fn set_im_checked(&mut self, buf: &Buffer, name: &str) -> Result<()> {
+        if let Some(controller) = self.get_controller(buf) {
+            let (_, current_group) = input_method_groups(controller)?;
+            if !group_input_methods(controller, &current_group)?
+                .iter()
+                .any(|im| im.unique_name == name)
+            {
+                return Err(Error::Failure(format!("unknown input method '{name}'")));
+            }
+        }
+        self.set_im(buf, name)
+    }
+
+    pub fn switch_im_group(&self, buf: &Buffer, group: &str) -> Result<()> {
+        if let Some(controller) = self.any_controller(buf) {
+            // fcitx5 silently ignores groups it does not know
+            let (groups, _) = input_method_groups(controller)?;
+            if !groups.iter().any(|name| name == group) {
+                return Err(Error::Failure(format!(
+                    "unknown input method group '{group}'"
+                )));
+            }
+            controller.switch_input_method_group(group)?;
+            if self.initialized(buf) {
+                self.refresh_im_cache(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn key_options(&self) -> KeyOptions {
+        self.config
+            .as_ref()
+            .map(|config| config.keys.clone())
+            .unwrap_or_default()
+    }
+
     pub fn store_original_keymaps(&mut self, buf: &Buffer) -> oxi::Result<()> {
         for km in buf.get_keymap(api::types::Mode::Insert)? {
             let key = km.lhs.to_lowercase();
             if KEYMAPS
                 .keys()
-                .chain(PASSTHROUGH_KEYMAPS.keys())
+                .chain(self.key_options().intercept.iter())
                 .any(|k| k.to_lowercase() == key)
             {
                 let new_buf_keymaps = if let Some(mut buf_keymaps) =
@@ -205,6 +571,7 @@ pub static PLUGIN_NAME: &str = "fcitx5-ui-rs.nvim";
 lazy_static::lazy_static! {
     static ref PLUGIN_STATE: Arc<Mutex<Fcitx5Plugin>> = Arc::new(Mutex::new(Fcitx5Plugin::new()));
     static ref IM_WINDOW: Arc<Mutex<Option<nvim_oxi::api::Window>>> = Arc::new(Mutex::new(None));
+    static ref CURRENT_IM: Arc<Mutex<HashMap<ContextKey, CachedIM>>> = Arc::new(Mutex::new(HashMap::new()));
 }
 
 // Get a reference to the global state
@@ -223,3 +590,20 @@ pub fn get_im_window_state() -> Arc<Mutex<IMWindowState>> {
 pub fn get_im_window() -> Arc<Mutex<Option<nvim_oxi::api::Window>>> {
     IM_WINDOW.clone()
 }
+
+// Update the cached current input method of the input context keyed by `key`, see
+// [`CachedIM`]
+pub fn cache_current_im(key: ContextKey, name: String) {
+    lock_logged!(CURRENT_IM, "CurrentIM").insert(
+        key,
+        CachedIM {
+            name,
+            updated_at: Instant::now(),
+        },
+    );
+}
+
+// Forget the cached current input method of the input context keyed by `key`
+pub fn forget_current_im(key: &ContextKey) {
+    lock_logged!(CURRENT_IM, "CurrentIM").remove(key);
+}
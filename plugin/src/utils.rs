@@ -9,8 +9,6 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use lazy_static::lazy_static;
 
-pub static CURSOR_INDICATOR: char = '│';
-
 #[macro_export]
 macro_rules! ignore_dbus_no_interface_error {
     ($expr:expr) => {
@@ -26,7 +24,7 @@ macro_rules! ignore_dbus_no_interface_error {
             {
                 let _ = nvim_oxi::api::echo(
                     vec![(
-                        "Input context gone, maybe fcitx5 restarted.  Ignoring.",
+                        "Input context gone, maybe fcitx5 restarted.  Will reconnect once it is back.",
                         Some("WarningMsg"),
                     )],
                     true,
@@ -51,6 +49,11 @@ pub fn as_api_error(e: impl std::error::Error) -> ApiError {
     ApiError::Other(e.to_string())
 }
 
+/// Whether Neovim is in insert mode
+pub fn in_insert_mode() -> bool {
+    std::str::from_utf8(api::get_mode().mode.as_bytes()) == Ok("i")
+}
+
 /// Delegate to the VimL function nvim_feedkeys() (:h nvim_feedkeys())
 /// We use this instead of [`nvim_oxi::api::replace_termcodes`] with [`nvim_oxi::api::feedkeys`],
 /// because <Esc>, <Left>, <Right> do not work properly with those (as of nvim-oxi v0.5.1).